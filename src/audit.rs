@@ -0,0 +1,189 @@
+use std::fs::{File, OpenOptions};
+use std::io::Write;
+use std::path::Path;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use anyhow::Context;
+use serde::Serialize;
+
+use crate::{ProcessResult, process_ext};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum AuditAction {
+    Kill,
+    Unlock,
+    Suspend,
+    Resume,
+}
+
+/// One line of the audit log.
+#[derive(Debug, Serialize)]
+pub struct AuditRecord<'a> {
+    /// UTC time of the action, RFC 3339.
+    pub time: String,
+    /// `DOMAIN\user` running locksmith.
+    pub user: &'a str,
+    /// The path whose lockers are acted on.
+    pub target_path: &'a str,
+    pub pid: u32,
+    /// `null` if the image path could not be queried.
+    pub image_path: Option<&'a str>,
+    /// Creation time of the process, in 100ns intervals since January 1, 1601 (UTC).
+    pub create_time: u64,
+    pub action: AuditAction,
+    /// Handle value closed by an unlock.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub handle: Option<String>,
+    /// `success` or `failure`.
+    pub result: &'static str,
+    pub error: Option<String>,
+}
+
+/// Append-only JSON-lines record of every kill, unlock, suspend and resume.
+///
+/// Disabled unless a log path is given with `--audit-log` or `LOCKSMITH_AUDIT_LOG`, in which
+/// case failing to open the log stops locksmith before it touches any process.
+pub struct AuditLog {
+    file: Option<File>,
+    user: String,
+    target_path: String,
+}
+
+impl AuditLog {
+    pub fn open(log_path: Option<&Path>, target_path: &str) -> anyhow::Result<Self> {
+        let Some(log_path) = log_path else {
+            return Ok(Self::disabled());
+        };
+
+        let file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(log_path)
+            .with_context(|| format!("Failed to open audit log '{}'", log_path.display()))?;
+        let user = match process_ext::pid_to_user(std::process::id()) {
+            Ok((user, domain)) => format!("{domain}\\{user}"),
+            Err(_) => "unknown".to_string(),
+        };
+
+        Ok(Self {
+            file: Some(file),
+            user,
+            target_path: target_path.to_string(),
+        })
+    }
+
+    pub fn disabled() -> Self {
+        Self {
+            file: None,
+            user: String::new(),
+            target_path: String::new(),
+        }
+    }
+
+    /// Appends a record for an action on `locker`, a failure to write is reported but does not
+    /// stop the remaining actions.
+    pub fn record<T>(
+        &mut self,
+        locker: &ProcessResult,
+        action: AuditAction,
+        handle: Option<usize>,
+        result: &anyhow::Result<T>,
+    ) {
+        let Some(file) = &mut self.file else {
+            return;
+        };
+
+        let record = AuditRecord {
+            time: format_utc(SystemTime::now()),
+            user: &self.user,
+            target_path: &self.target_path,
+            pid: locker.pid,
+            image_path: locker.path.as_deref(),
+            create_time: locker.create_time,
+            action,
+            handle: handle.map(|handle| format!("0x{handle:x}")),
+            result: if result.is_ok() { "success" } else { "failure" },
+            error: result.as_ref().err().map(|err| format!("{err:#}")),
+        };
+
+        // One write per record, so that the lines of concurrent runs appending to the same log
+        // do not interleave.
+        let write_result = serde_json::to_string(&record)
+            .map_err(anyhow::Error::from)
+            .and_then(|mut line| {
+                line.push('\n');
+                Ok(file.write_all(line.as_bytes())?)
+            })
+            .and_then(|()| Ok(file.flush()?));
+        if let Err(err) = write_result {
+            eprintln!("Error: Failed to write audit record: {err:#}");
+        }
+    }
+}
+
+/// Formats a time as RFC 3339 in UTC with millisecond precision.
+pub fn format_utc(time: SystemTime) -> String {
+    let since_epoch = time.duration_since(UNIX_EPOCH).unwrap_or_default();
+    let secs = since_epoch.as_secs();
+    let (year, month, day) = civil_from_days((secs / 86_400) as i64);
+    let secs_of_day = secs % 86_400;
+    format!(
+        "{year:04}-{month:02}-{day:02}T{:02}:{:02}:{:02}.{:03}Z",
+        secs_of_day / 3600,
+        secs_of_day / 60 % 60,
+        secs_of_day % 60,
+        since_epoch.subsec_millis()
+    )
+}
+
+/// Converts days since 1970-01-01 to a (year, month, day) date.
+///
+/// See http://howardhinnant.github.io/date_algorithms.html#civil_from_days
+fn civil_from_days(days: i64) -> (i64, u32, u32) {
+    let z = days + 719_468;
+    let era = z.div_euclid(146_097);
+    let doe = z.rem_euclid(146_097);
+    let yoe = (doe - doe / 1460 + doe / 36_524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = (doy - (153 * mp + 2) / 5 + 1) as u32;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 } as u32;
+    let year = yoe + era * 400 + i64::from(month <= 2);
+    (year, month, day)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    #[test]
+    fn test_format_utc() {
+        assert_eq!(format_utc(UNIX_EPOCH), "1970-01-01T00:00:00.000Z");
+        assert_eq!(
+            format_utc(UNIX_EPOCH + Duration::from_millis(1_709_210_096_789)),
+            "2024-02-29T12:34:56.789Z"
+        );
+    }
+
+    #[test]
+    fn test_audit_record_serialization() {
+        let record = AuditRecord {
+            time: "2024-02-29T12:34:56.789Z".to_string(),
+            user: r"CONTOSO\builder",
+            target_path: r"C:\build\out.dll",
+            pid: 1234,
+            image_path: Some(r"C:\Windows\notepad.exe"),
+            create_time: 133_534_000_000_000_000,
+            action: AuditAction::Unlock,
+            handle: Some("0x1a4".to_string()),
+            result: "failure",
+            error: Some("Access is denied.".to_string()),
+        };
+        assert_eq!(
+            serde_json::to_string(&record).unwrap(),
+            r#"{"time":"2024-02-29T12:34:56.789Z","user":"CONTOSO\\builder","target_path":"C:\\build\\out.dll","pid":1234,"image_path":"C:\\Windows\\notepad.exe","create_time":133534000000000000,"action":"unlock","handle":"0x1a4","result":"failure","error":"Access is denied."}"#
+        );
+    }
+}
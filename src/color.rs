@@ -0,0 +1,79 @@
+use std::env;
+use std::io::{self, IsTerminal};
+
+use clap::ValueEnum;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum ColorChoice {
+    /// Colorize when writing to a terminal, honouring `NO_COLOR` and `CLICOLOR_FORCE`
+    Auto,
+    /// Always colorize
+    Always,
+    /// Never colorize
+    Never,
+}
+
+/// Applies the color choice to every message printed through `colored`.
+pub fn init(choice: ColorChoice) {
+    let enabled = should_colorize(
+        choice,
+        env::var_os("NO_COLOR").is_some_and(|v| !v.is_empty()),
+        env::var_os("CLICOLOR_FORCE").is_some_and(|v| !v.is_empty() && v != "0"),
+        io::stdout().is_terminal() && io::stderr().is_terminal(),
+    );
+
+    if enabled {
+        // Older consoles only render ANSI escapes once virtual terminal processing is on.
+        let _ = colored::control::set_virtual_terminal(true);
+    }
+    colored::control::set_override(enabled);
+}
+
+/// Decides whether output should be colorized.
+///
+/// An explicit `--color always|never` wins, otherwise `CLICOLOR_FORCE` forces color on,
+/// `NO_COLOR` forces it off, and color is only used when the output is a terminal.
+pub fn should_colorize(
+    choice: ColorChoice,
+    no_color: bool,
+    clicolor_force: bool,
+    is_terminal: bool,
+) -> bool {
+    match choice {
+        ColorChoice::Always => true,
+        ColorChoice::Never => false,
+        ColorChoice::Auto => {
+            if clicolor_force {
+                true
+            } else if no_color {
+                false
+            } else {
+                is_terminal
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_should_colorize_explicit_choice_wins() {
+        assert!(should_colorize(ColorChoice::Always, true, false, false));
+        assert!(!should_colorize(ColorChoice::Never, false, true, true));
+    }
+
+    #[test]
+    fn test_should_colorize_auto_follows_terminal() {
+        assert!(should_colorize(ColorChoice::Auto, false, false, true));
+        assert!(!should_colorize(ColorChoice::Auto, false, false, false));
+    }
+
+    #[test]
+    fn test_should_colorize_auto_honours_env() {
+        assert!(!should_colorize(ColorChoice::Auto, true, false, true));
+        assert!(should_colorize(ColorChoice::Auto, false, true, false));
+        assert!(should_colorize(ColorChoice::Auto, true, true, false));
+    }
+}
@@ -0,0 +1,249 @@
+use std::collections::HashSet;
+
+use anyhow::Context;
+
+use crate::process_ext::{self, ProcessIdentity, ProcessInfo, ProcessTable};
+
+/// Restricts a scan to the processes the user cares about.
+///
+/// Pids and image names come from the process snapshot, so they are checked first. The image
+/// path and the user need to open the process, which is only done for the processes that
+/// passed the other checks. Processes that started after the snapshot are checked with
+/// [`ProcessFilter::matches_live`] instead.
+#[derive(Debug, Default)]
+pub struct ProcessFilter {
+    pub pids: Vec<u32>,
+    pub names: Vec<String>,
+    pub images: Vec<String>,
+    pub users: Vec<String>,
+    pub exclude_names: Vec<String>,
+}
+
+impl ProcessFilter {
+    /// Also keeps only the processes running as the current user.
+    pub fn with_current_user(mut self) -> anyhow::Result<Self> {
+        let (user, domain) = process_ext::pid_to_user(std::process::id())
+            .context("Failed to look up the current user")?;
+        self.users.push(format!("{domain}\\{user}"));
+        Ok(self)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.pids.is_empty()
+            && self.names.is_empty()
+            && self.images.is_empty()
+            && self.users.is_empty()
+            && self.exclude_names.is_empty()
+    }
+
+    /// Returns the pids of the processes of the table that pass the filter.
+    pub fn select(&self, process_table: &ProcessTable) -> HashSet<u32> {
+        process_table
+            .as_slice()
+            .iter()
+            .filter(|process_info| {
+                self.matches(
+                    process_info,
+                    || process_ext::process_image_path(&process_info.identity()).ok(),
+                    || process_ext::process_user(&process_info.identity()).ok(),
+                )
+            })
+            .map(|process_info| process_info.pid)
+            .collect()
+    }
+
+    /// Checks a process that is missing from the snapshot, looking everything up from the
+    /// live process. A process that cannot be opened does not match.
+    pub fn matches_live(&self, pid: u32) -> bool {
+        let Ok(identity) = ProcessIdentity::query(pid) else {
+            return false;
+        };
+        let image_path = process_ext::process_image_path(&identity).ok();
+        let process_info = ProcessInfo {
+            pid,
+            create_time: identity.create_time,
+            process_name: image_path
+                .as_deref()
+                .map(process_ext::image_name)
+                .unwrap_or_default()
+                .to_string(),
+            ..Default::default()
+        };
+        self.matches(
+            &process_info,
+            || image_path,
+            || process_ext::process_user(&identity).ok(),
+        )
+    }
+
+    /// Checks a process, looking up its image path and its `(user, domain)` only when needed.
+    ///
+    /// A process whose image path or user cannot be looked up does not match the filters on
+    /// them.
+    fn matches(
+        &self,
+        process_info: &ProcessInfo,
+        image_path: impl FnOnce() -> Option<String>,
+        user: impl FnOnce() -> Option<(String, String)>,
+    ) -> bool {
+        let name = process_info.process_name.as_str();
+        if !self.pids.is_empty() && !self.pids.contains(&process_info.pid) {
+            return false;
+        }
+        if !self.names.is_empty() && !self.names.iter().any(|glob| glob_match(glob, name)) {
+            return false;
+        }
+        if self.exclude_names.iter().any(|glob| glob_match(glob, name)) {
+            return false;
+        }
+
+        if !self.images.is_empty() {
+            let Some(image_path) = image_path() else {
+                return false;
+            };
+            if !self.images.iter().any(|glob| glob_match(glob, &image_path)) {
+                return false;
+            }
+        }
+
+        if !self.users.is_empty() {
+            let Some((user, domain)) = user() else {
+                return false;
+            };
+            let qualified = format!("{domain}\\{user}");
+            if !self.users.iter().any(|wanted| {
+                wanted.eq_ignore_ascii_case(&user) || wanted.eq_ignore_ascii_case(&qualified)
+            }) {
+                return false;
+            }
+        }
+
+        true
+    }
+}
+
+/// Matches `text` against a pattern where `*` stands for any run of characters and `?` for
+/// one character, ignoring ASCII case.
+fn glob_match(pattern: &str, text: &str) -> bool {
+    let pattern: Vec<char> = pattern.chars().map(|c| c.to_ascii_lowercase()).collect();
+    let text: Vec<char> = text.chars().map(|c| c.to_ascii_lowercase()).collect();
+
+    let (mut p, mut t) = (0, 0);
+    // Position of the last `*` and of the text it was tried against, to backtrack to.
+    let mut star: Option<(usize, usize)> = None;
+    while t < text.len() {
+        match pattern.get(p) {
+            Some('*') => {
+                star = Some((p, t));
+                p += 1;
+            }
+            Some(&c) if c == '?' || c == text[t] => {
+                p += 1;
+                t += 1;
+            }
+            _ => match star {
+                // Let the last `*` swallow one more character.
+                Some((star_p, star_t)) => {
+                    star = Some((star_p, star_t + 1));
+                    p = star_p + 1;
+                    t = star_t + 1;
+                }
+                None => return false,
+            },
+        }
+    }
+    pattern[p..].iter().all(|&c| c == '*')
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn process(pid: u32, name: &str) -> ProcessInfo {
+        ProcessInfo {
+            pid,
+            process_name: name.to_string(),
+            ..Default::default()
+        }
+    }
+
+    fn no_image() -> Option<String> {
+        None
+    }
+
+    fn no_user() -> Option<(String, String)> {
+        None
+    }
+
+    #[test]
+    fn test_glob_match() {
+        assert!(glob_match("explorer.exe", "Explorer.EXE"));
+        assert!(glob_match("*.exe", "notepad.exe"));
+        assert!(glob_match("note?ad*", "notepad.exe"));
+        assert!(glob_match(
+            r"C:\Program Files\*",
+            r"c:\program files\App\app.exe"
+        ));
+        assert!(glob_match("*a*b*", "xxaxxbxx"));
+        assert!(glob_match("*", ""));
+        assert!(!glob_match("*.exe", "notepad.dll"));
+        assert!(!glob_match("note?ad", "notepad.exe"));
+        assert!(!glob_match("?", ""));
+        assert!(!glob_match("*a*b", "xxaxxbxx"));
+    }
+
+    #[test]
+    fn test_empty_filter_matches_everything() {
+        let filter = ProcessFilter::default();
+        assert!(filter.is_empty());
+        assert!(filter.matches(&process(4, "System"), no_image, no_user));
+    }
+
+    #[test]
+    fn test_filter_by_pid_and_name() {
+        let filter = ProcessFilter {
+            pids: vec![10, 20],
+            names: vec!["code*".to_string()],
+            exclude_names: vec!["*helper*".to_string()],
+            ..Default::default()
+        };
+        assert!(filter.matches(&process(10, "Code.exe"), no_image, no_user));
+        assert!(!filter.matches(&process(30, "Code.exe"), no_image, no_user));
+        assert!(!filter.matches(&process(20, "notepad.exe"), no_image, no_user));
+        assert!(!filter.matches(&process(20, "CodeHelper.exe"), no_image, no_user));
+    }
+
+    #[test]
+    fn test_filter_looks_up_only_what_it_needs() {
+        let filter = ProcessFilter {
+            names: vec!["explorer.exe".to_string()],
+            users: vec!["alice".to_string()],
+            ..Default::default()
+        };
+        // Rejected by name, the user is never looked up.
+        assert!(!filter.matches(&process(1, "notepad.exe"), no_image, || {
+            panic!("user looked up")
+        }));
+
+        let user = || Some(("Alice".to_string(), "CORP".to_string()));
+        assert!(filter.matches(&process(1, "explorer.exe"), no_image, user));
+        let bob = || Some(("bob".to_string(), "CORP".to_string()));
+        assert!(!filter.matches(&process(1, "explorer.exe"), no_image, bob));
+        assert!(!filter.matches(&process(1, "explorer.exe"), no_image, no_user));
+    }
+
+    #[test]
+    fn test_filter_by_qualified_user_and_image() {
+        let filter = ProcessFilter {
+            images: vec![r"C:\Tools\*".to_string()],
+            users: vec![r"corp\alice".to_string()],
+            ..Default::default()
+        };
+        let user = || Some(("alice".to_string(), "CORP".to_string()));
+        let tool = || Some(r"C:\Tools\build.exe".to_string());
+        let system = || Some(r"C:\Windows\explorer.exe".to_string());
+        assert!(filter.matches(&process(1, "build.exe"), tool, user));
+        assert!(!filter.matches(&process(1, "explorer.exe"), system, user));
+        assert!(!filter.matches(&process(1, "build.exe"), no_image, user));
+    }
+}
@@ -0,0 +1,190 @@
+use std::path::Path;
+use std::process::{Command, ExitCode};
+use std::sync::{Mutex, MutexGuard, PoisonError};
+
+use clap::Args;
+use colored::Colorize;
+use windows::Win32::System::Console::{
+    CTRL_BREAK_EVENT, CTRL_C_EVENT, CTRL_CLOSE_EVENT, CTRL_LOGOFF_EVENT, CTRL_SHUTDOWN_EVENT,
+    SetConsoleCtrlHandler,
+};
+use windows::core::BOOL;
+
+use crate::audit::{AuditAction, AuditLog};
+use crate::kill::{exclude_protected, print_skipped};
+use crate::process_ext::{self, SuspendedProcess};
+use crate::protect::{Denylist, ProtectRule};
+use crate::{EXIT_ERROR, ProcessResult, ScanOptions, find_locker};
+
+#[derive(Args, Debug)]
+pub struct FreezeArgs {
+    /// Path to the file whose lockers should be suspended
+    pub path: String,
+
+    /// Never suspend this process, given as a pid, an image name or an image path
+    /// (can be repeated)
+    #[arg(long, value_name = "PID|NAME|PATH")]
+    pub protect: Vec<ProtectRule>,
+
+    /// Also suspend protected processes such as csrss.exe, the System process or
+    /// locksmith's parent
+    #[arg(long, default_value_t = false)]
+    pub allow_protected: bool,
+
+    /// Command to run while the lockers are suspended
+    #[arg(last = true, required = true, value_name = "COMMAND")]
+    pub command: Vec<String>,
+}
+
+/// Suspends every locker of the path, runs the command and resumes the lockers.
+///
+/// Exits with the exit code of the command, 255 if it does not fit in a byte, or 3 if the
+/// command could not be run at all.
+pub fn run(
+    args: &FreezeArgs,
+    scan_options: &ScanOptions,
+    audit_log_path: Option<&Path>,
+) -> ExitCode {
+    let scan = match find_locker(std::slice::from_ref(&args.path), scan_options) {
+        Ok(scan) => scan,
+        Err(err) => {
+            eprintln!("Error: {err:#}");
+            return ExitCode::from(EXIT_ERROR);
+        }
+    };
+    for err in &scan.errors {
+        eprintln!("Warning: {err:#}");
+    }
+
+    let targets: Vec<_> = scan.lockers.values().collect();
+    let (targets, skipped) = if args.allow_protected {
+        (targets, Vec::new())
+    } else {
+        exclude_protected(
+            targets,
+            &Denylist::new(&args.protect).with_ancestors(scan.processes.as_slice()),
+        )
+    };
+    print_skipped(&skipped);
+
+    let audit_log = match AuditLog::open(audit_log_path, &args.path) {
+        Ok(audit_log) => audit_log,
+        Err(err) => {
+            eprintln!("Error: {err:#}");
+            return ExitCode::from(EXIT_ERROR);
+        }
+    };
+    *frozen() = Some(Frozen {
+        audit_log,
+        suspended: Vec::with_capacity(targets.len()),
+    });
+
+    // Ctrl-C reaches every process attached to the console, locksmith has to survive it to
+    // resume the lockers, the command still gets it and decides on its own.
+    if let Err(err) = unsafe { SetConsoleCtrlHandler(Some(console_handler), true) } {
+        eprintln!("Error: Failed to install the console handler: {err}");
+        return ExitCode::from(EXIT_ERROR);
+    }
+
+    for target in targets {
+        let result = process_ext::suspend_process(&target.identity());
+        let mut frozen = frozen();
+        let Some(frozen) = frozen.as_mut() else {
+            // The console handler already resumed everything, locksmith is about to exit.
+            break;
+        };
+        frozen
+            .audit_log
+            .record(target, AuditAction::Suspend, None, &result);
+        match result {
+            Ok(process) => {
+                println!(
+                    "Suspended PID {}, Name: '{}' ({} thread(s)).",
+                    target.pid,
+                    target.name,
+                    process.thread_count()
+                );
+                frozen.suspended.push((target.clone(), process));
+            }
+            Err(e) => eprintln!(
+                "{}",
+                format!(
+                    "Failed to suspend PID {}, Name: '{}', it may still hold the file: {e:#}",
+                    target.pid, target.name
+                )
+                .yellow()
+            ),
+        }
+    }
+
+    let status = Command::new(&args.command[0])
+        .args(&args.command[1..])
+        .status();
+
+    // Always resume, whatever happened to the command. The lock is held until every locker is
+    // resumed, so that a console handler firing meanwhile waits for it.
+    if let Some(frozen) = frozen().as_mut() {
+        frozen.resume(true);
+    }
+
+    let _ = unsafe { SetConsoleCtrlHandler(Some(console_handler), false) };
+    frozen().take();
+
+    match status {
+        Ok(status) => {
+            let code = status.code().unwrap_or(1);
+            ExitCode::from(u8::try_from(code).unwrap_or(u8::MAX))
+        }
+        Err(err) => {
+            eprintln!("Error: Failed to run '{}': {err}", args.command[0]);
+            ExitCode::from(EXIT_ERROR)
+        }
+    }
+}
+
+/// The lockers suspended by [`run`], kept where [`console_handler`] can reach them.
+static FROZEN: Mutex<Option<Frozen>> = Mutex::new(None);
+
+struct Frozen {
+    audit_log: AuditLog,
+    suspended: Vec<(ProcessResult, SuspendedProcess)>,
+}
+
+impl Frozen {
+    /// Resumes every suspended locker, `report` prints the outcome on the console.
+    fn resume(&mut self, report: bool) {
+        for (target, process) in self.suspended.drain(..) {
+            let pid = target.pid;
+            let result = match process.resume() {
+                0 => Ok(()),
+                failed_count => Err(anyhow::anyhow!("Failed to resume {failed_count} thread(s)")),
+            };
+            self.audit_log
+                .record(&target, AuditAction::Resume, None, &result);
+            match result {
+                Ok(()) if report => println!("Resumed PID {pid}."),
+                Err(e) if report => eprintln!("{}", format!("{e} of PID {pid}.").bold().red()),
+                _ => {}
+            }
+        }
+    }
+}
+
+fn frozen() -> MutexGuard<'static, Option<Frozen>> {
+    FROZEN.lock().unwrap_or_else(PoisonError::into_inner)
+}
+
+unsafe extern "system" fn console_handler(ctrl_type: u32) -> BOOL {
+    match ctrl_type {
+        CTRL_C_EVENT | CTRL_BREAK_EVENT => true.into(),
+        // Windows terminates locksmith once this returns, resume the lockers first rather than
+        // leave them suspended for good. The console may already be gone, so nothing is printed.
+        CTRL_CLOSE_EVENT | CTRL_LOGOFF_EVENT | CTRL_SHUTDOWN_EVENT => {
+            if let Some(mut frozen) = frozen().take() {
+                frozen.resume(false);
+            }
+            false.into()
+        }
+        _ => false.into(),
+    }
+}
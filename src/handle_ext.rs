@@ -1,7 +1,18 @@
+use std::cell::RefCell;
+use std::collections::BTreeMap;
 use std::ffi::c_void;
+use std::fs::File;
+use std::num::NonZeroUsize;
+use std::os::windows::io::AsRawHandle;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::mpsc::{self, Receiver};
+use std::thread::{self, JoinHandle};
+use std::time::Duration;
 
 use anyhow::anyhow;
 use log::debug;
+use serde::Serialize;
 use windows::{
     Wdk::{
         Foundation::{OBJECT_INFORMATION_CLASS, OBJECT_NAME_INFORMATION, ObjectTypeInformation},
@@ -9,8 +20,9 @@ use windows::{
     },
     Win32::{
         Foundation::{
-            DUPLICATE_SAME_ACCESS, DuplicateHandle, ERROR_ACCESS_DENIED, ERROR_INVALID_HANDLE,
-            ERROR_NOT_SUPPORTED, HANDLE,
+            DUPLICATE_CLOSE_SOURCE, DUPLICATE_HANDLE_OPTIONS, DUPLICATE_SAME_ACCESS,
+            DuplicateHandle, ERROR_ACCESS_DENIED, ERROR_INVALID_HANDLE, ERROR_NOT_SUPPORTED,
+            HANDLE,
         },
         Storage::FileSystem::{FILE_TYPE_DISK, GetFileType},
         System::{
@@ -20,101 +32,397 @@ use windows::{
     },
 };
 
+use crate::process_ext::ProcessIdentity;
 use crate::string_ext::ToString;
+use crate::watchdog::Watchdog;
 use crate::{nt_ext, safe_handle::SafeHandle};
 
-#[derive(Debug)]
+/// `HandleAttributes` flag set on handles that cannot be closed with `CloseHandle`.
+const OBJ_PROTECT_CLOSE: u32 = 0x0000_0001;
+
+/// How long the name query of a single handle may take. Synchronous pipes and some driver
+/// devices block `NtQueryObject` forever.
+const NAME_QUERY_TIMEOUT: Duration = Duration::from_millis(500);
+
+#[derive(Debug, Clone, Default, Serialize)]
 pub struct HandleInfo {
     pub pid: u32,
+    pub handle_value: usize,
+    pub attributes: u32,
     pub nt_path: String,
 }
 
-pub fn enum_handles() -> anyhow::Result<Vec<HandleInfo>> {
+impl HandleInfo {
+    pub fn is_protected_from_close(&self) -> bool {
+        self.attributes & OBJ_PROTECT_CLOSE != 0
+    }
+}
+
+/// The file handles held by one process.
+#[derive(Debug, Default)]
+pub struct ProcessHandles {
+    pub pid: u32,
+    pub handles: Vec<HandleInfo>,
+    /// Values of the handles whose name query did not return in time.
+    pub unresolved: Vec<usize>,
+}
+
+/// What became of one entry of the handle table.
+#[derive(Debug)]
+enum Resolution {
+    File(HandleInfo),
+    /// The name query did not return before `NAME_QUERY_TIMEOUT`.
+    Unresolved,
+    /// Not a file on disk, or the handle could not be inspected.
+    Skipped,
+}
+
+thread_local! {
+    static NAME_QUERIES: RefCell<Watchdog<SafeHandle, anyhow::Result<String>>> =
+        RefCell::new(Watchdog::new(|handle| handle_to_nt_path(&handle), NAME_QUERY_TIMEOUT));
+}
+
+/// Starts resolving the file handles of every process `include_pid` accepts, see
+/// [`HandleStream`].
+pub fn stream_handles(
+    jobs: NonZeroUsize,
+    max_buffer: usize,
+    include_pid: impl Fn(u32) -> bool,
+) -> anyhow::Result<HandleStream> {
     const SYSTEM_EXTENDED_HANDLE_INFORMATION: SYSTEM_INFORMATION_CLASS =
         SYSTEM_INFORMATION_CLASS(64);
 
-    let buffer = nt_ext::nt_query_information_loop(SYSTEM_EXTENDED_HANDLE_INFORMATION)?;
+    // Object type indexes differ between Windows builds, a file handle of our own that is open
+    // while the table is captured reveals the one of `File`. The null device never matches a
+    // path on disk, so it cannot show up as a locker itself.
+    let probe = File::open("NUL");
 
-    let handle_info = unsafe {
-        if buffer.len() < std::mem::size_of::<SystemHandleInformationEx>() {
-            return Err(anyhow!("Buffer too small for SystemHandleInformationEx"));
+    let buffer = nt_ext::nt_query_information_loop(SYSTEM_EXTENDED_HANDLE_INFORMATION, max_buffer)?;
+    let mut handle_entries = parse_handle_table(&buffer)?;
+
+    let file_type_index = match &probe {
+        Ok(probe) => file_type_index(
+            &handle_entries,
+            std::process::id(),
+            probe.as_raw_handle() as usize,
+        ),
+        Err(err) => {
+            debug!("Failed to open the file type probe, error: {err:?}");
+            None
         }
-        std::ptr::read_unaligned(buffer.as_ptr() as *const SystemHandleInformationEx)
     };
+    match file_type_index {
+        Some(file_type_index) => {
+            handle_entries.retain(|handle_entry| handle_entry.object_type_index == file_type_index)
+        }
+        None => debug!("File object type index not found, checking the type of every handle"),
+    }
+    let type_checked = file_type_index.is_some();
+    handle_entries.retain(|handle_entry| include_pid(handle_entry.unique_process_id as u32));
 
-    let handle_count = handle_info.number_of_handles;
+    Ok(stream_by_process(
+        handle_entries,
+        jobs,
+        |pid| unsafe { OpenProcess(PROCESS_DUP_HANDLE, false, pid) }.map(SafeHandle::new),
+        move |safe_process_handle, handle_entry| {
+            get_handle_info(safe_process_handle, handle_entry, type_checked)
+        },
+    ))
+}
+
+/// Finds the object type index of `File` from a file handle the process `pid` is known to hold.
+fn file_type_index(
+    handle_entries: &[SystemHandleTableEntryInfoEx],
+    pid: u32,
+    handle_value: usize,
+) -> Option<u16> {
+    handle_entries
+        .iter()
+        .find(|handle_entry| {
+            handle_entry.unique_process_id == pid as usize
+                && handle_entry.handle_value == handle_value
+        })
+        .map(|handle_entry| handle_entry.object_type_index)
+}
+
+/// Reads the entries of a `SystemHandleInformationEx` buffer.
+fn parse_handle_table(buffer: &[u8]) -> anyhow::Result<Vec<SystemHandleTableEntryInfoEx>> {
+    if buffer.len() < std::mem::size_of::<SystemHandleInformationEx>() {
+        return Err(anyhow!("Buffer too small for SystemHandleInformationEx"));
+    }
+    let handle_info =
+        unsafe { std::ptr::read_unaligned(buffer.as_ptr() as *const SystemHandleInformationEx) };
 
     // Skip over the first two fields of the SystemHandleInformationEx struct.
-    let mut offset = 2 * std::mem::size_of::<usize>();
+    let offset = 2 * std::mem::size_of::<usize>();
+    let entry_size = std::mem::size_of::<SystemHandleTableEntryInfoEx>();
+    let available = (buffer.len() - offset) / entry_size;
+    if handle_info.number_of_handles > available {
+        return Err(anyhow!(
+            "SystemHandleInformationEx claims {} handles but the buffer only holds {available}",
+            handle_info.number_of_handles
+        ));
+    }
 
-    let mut handle_info_collection = Vec::with_capacity(handle_count);
+    Ok((0..handle_info.number_of_handles)
+        .map(|index| unsafe {
+            std::ptr::read_unaligned(buffer.as_ptr().add(offset + index * entry_size)
+                as *const SystemHandleTableEntryInfoEx)
+        })
+        .collect())
+}
 
-    for _ in 0..handle_count {
-        let handle_entry: SystemHandleTableEntryInfoEx = unsafe {
-            std::ptr::read_unaligned(
-                buffer.as_ptr().add(offset) as *const SystemHandleTableEntryInfoEx
-            )
-        };
+/// File handles being resolved on background threads, one owning process at a time.
+///
+/// Iterating yields the handles of each process as soon as it is done, in no particular order.
+/// Dropping the stream stops the resolution, which is how a scan exits at the first locker.
+pub struct HandleStream {
+    batches: Receiver<ProcessHandles>,
+    cancelled: Arc<AtomicBool>,
+    workers: Vec<JoinHandle<()>>,
+}
 
-        offset += std::mem::size_of::<SystemHandleTableEntryInfoEx>();
+impl Iterator for HandleStream {
+    type Item = ProcessHandles;
 
-        if let Some(handle_info) = get_handle_info(handle_entry) {
-            handle_info_collection.push(handle_info);
+    fn next(&mut self) -> Option<Self::Item> {
+        self.batches.recv().ok()
+    }
+}
+
+impl Drop for HandleStream {
+    fn drop(&mut self) {
+        self.cancelled.store(true, Ordering::Relaxed);
+        for worker in self.workers.drain(..) {
+            let _ = worker.join();
         }
     }
+}
+
+/// Resolves the handle table one owning process at a time, on up to `jobs` threads.
+///
+/// The table holds hundreds of thousands of entries but only a few hundred processes, so each
+/// process is opened once and its handle reused for all of its entries. A process that cannot
+/// be opened is only tried, and logged, once.
+fn stream_by_process<P>(
+    handle_entries: Vec<SystemHandleTableEntryInfoEx>,
+    jobs: NonZeroUsize,
+    open_process: impl Fn(u32) -> windows::core::Result<P> + Send + Sync + 'static,
+    resolve: impl Fn(&P, &SystemHandleTableEntryInfoEx) -> Resolution + Send + Sync + 'static,
+) -> HandleStream {
+    let mut by_pid = BTreeMap::<u32, Vec<SystemHandleTableEntryInfoEx>>::new();
+    for handle_entry in handle_entries {
+        by_pid
+            .entry(handle_entry.unique_process_id as u32)
+            .or_default()
+            .push(handle_entry);
+    }
+    let mut batches: Vec<_> = by_pid.into_iter().collect();
+    // Hand out the biggest batches first so that one large process does not run alone at the end.
+    batches.sort_by_key(|(_, entries)| std::cmp::Reverse(entries.len()));
 
-    Ok(handle_info_collection)
+    let worker_count = jobs.get().min(batches.len());
+    let batches = Arc::new(batches);
+    let next = Arc::new(AtomicUsize::new(0));
+    let cancelled = Arc::new(AtomicBool::new(false));
+    let open_process = Arc::new(open_process);
+    let resolve = Arc::new(resolve);
+    let (sender, receiver) = mpsc::channel();
+
+    let workers = (0..worker_count)
+        .map(|_| {
+            let (batches, next, cancelled) = (batches.clone(), next.clone(), cancelled.clone());
+            let (open_process, resolve) = (open_process.clone(), resolve.clone());
+            let sender = sender.clone();
+            thread::spawn(move || {
+                while let Some((pid, entries)) = batches.get(next.fetch_add(1, Ordering::Relaxed)) {
+                    if cancelled.load(Ordering::Relaxed) {
+                        break;
+                    }
+                    let batch = resolve_batch(*pid, entries, &cancelled, &*open_process, &*resolve);
+                    if sender.send(batch).is_err() {
+                        break;
+                    }
+                }
+            })
+        })
+        .collect();
+
+    HandleStream {
+        batches: receiver,
+        cancelled,
+        workers,
+    }
 }
 
-pub fn get_handle_info(handle_entry: SystemHandleTableEntryInfoEx) -> Option<HandleInfo> {
-    let pid = handle_entry.unique_process_id as u32;
+/// Resolves the entries of one process with a single open of it.
+fn resolve_batch<P>(
+    pid: u32,
+    entries: &[SystemHandleTableEntryInfoEx],
+    cancelled: &AtomicBool,
+    open_process: impl Fn(u32) -> windows::core::Result<P>,
+    resolve: impl Fn(&P, &SystemHandleTableEntryInfoEx) -> Resolution,
+) -> ProcessHandles {
+    let mut process_handles = ProcessHandles {
+        pid,
+        ..Default::default()
+    };
 
-    let open_process_result = unsafe { OpenProcess(PROCESS_DUP_HANDLE, false, pid) };
-    match open_process_result {
+    let process = match open_process(pid) {
+        Ok(process) => process,
         Err(err) => {
             if err.code() != ERROR_ACCESS_DENIED.into() {
-                debug!("OpenProcess failed, pid: {pid}, error: {err:?}");
+                debug!(
+                    "OpenProcess failed, pid: {pid}, skipping {} handle(s), error: {err:?}",
+                    entries.len()
+                );
             }
-            None
+            return process_handles;
         }
-        Ok(process_handle) => {
-            let safe_process_handle = SafeHandle::new(process_handle);
-            let mut safe_dup_handle = SafeHandle::new(HANDLE::default());
-            if let Err(err) = unsafe {
-                DuplicateHandle(
-                    safe_process_handle.handle,
-                    HANDLE(handle_entry.handle_value as isize as *mut c_void),
-                    GetCurrentProcess(),
-                    &mut safe_dup_handle.handle,
-                    0,
-                    false,
-                    DUPLICATE_SAME_ACCESS,
-                )
-            } {
-                if err.code() != ERROR_NOT_SUPPORTED.into()
-                    && err.code() != ERROR_ACCESS_DENIED.into()
-                    && err.code() != ERROR_INVALID_HANDLE.into()
-                {
-                    debug!("DuplicateHandle failed, pid: {pid}, error: {err:?}");
-                }
-                return None;
-            }
+    };
 
-            match is_handle_type_file(&safe_dup_handle) {
-                Ok(true) => {}
-                Ok(false) | Err(_) => return None,
+    for entry in entries {
+        if cancelled.load(Ordering::Relaxed) {
+            break;
+        }
+        match resolve(&process, entry) {
+            Resolution::File(handle_info) => process_handles.handles.push(handle_info),
+            Resolution::Unresolved => {
+                debug!(
+                    "Name query timed out, pid: {pid}, handle: 0x{:x}",
+                    entry.handle_value
+                );
+                process_handles.unresolved.push(entry.handle_value);
             }
+            Resolution::Skipped => {}
+        }
+    }
+    process_handles
+}
 
-            let handle_to_nt_path_result = handle_to_nt_path(&safe_dup_handle);
-            match handle_to_nt_path_result {
-                Ok(nt_path) => Some(HandleInfo { pid, nt_path }),
-                Err(err) => {
-                    debug!("handle_to_nt_path failed, pid: {pid}, error: {err:?}");
-                    None
-                }
+/// Resolves an entry of the handle table to a file path, using a `PROCESS_DUP_HANDLE` handle to
+/// its owning process.
+///
+/// `type_checked` tells that the entry is already known to be a `File` from its type index.
+fn get_handle_info(
+    safe_process_handle: &SafeHandle,
+    handle_entry: &SystemHandleTableEntryInfoEx,
+    type_checked: bool,
+) -> Resolution {
+    let pid = handle_entry.unique_process_id as u32;
+
+    let safe_dup_handle = match duplicate_handle(
+        safe_process_handle,
+        handle_entry.handle_value,
+        DUPLICATE_SAME_ACCESS,
+    ) {
+        Ok(safe_dup_handle) => safe_dup_handle,
+        Err(err) => {
+            if err.code() != ERROR_NOT_SUPPORTED.into()
+                && err.code() != ERROR_ACCESS_DENIED.into()
+                && err.code() != ERROR_INVALID_HANDLE.into()
+            {
+                debug!("DuplicateHandle failed, pid: {pid}, error: {err:?}");
             }
+            return Resolution::Skipped;
         }
+    };
+
+    let is_file = if type_checked {
+        is_disk_file(&safe_dup_handle)
+    } else {
+        is_handle_type_file(&safe_dup_handle).unwrap_or(false)
+    };
+    if !is_file {
+        return Resolution::Skipped;
+    }
+
+    match query_nt_path(safe_dup_handle) {
+        Some(Ok(nt_path)) => Resolution::File(HandleInfo {
+            pid,
+            handle_value: handle_entry.handle_value,
+            attributes: handle_entry.handle_attributes,
+            nt_path,
+        }),
+        Some(Err(err)) => {
+            debug!("handle_to_nt_path failed, pid: {pid}, error: {err:?}");
+            Resolution::Skipped
+        }
+        None => Resolution::Unresolved,
+    }
+}
+
+/// Resolves the NT path of a handle on a watchdog thread, `None` if the query got stuck.
+///
+/// The handle moves to the query thread, so it stays open for as long as a stuck query uses it.
+fn query_nt_path(safe_file_handle: SafeHandle) -> Option<anyhow::Result<String>> {
+    NAME_QUERIES.with(|watchdog| watchdog.borrow_mut().run(safe_file_handle))
+}
+
+/// Duplicates a handle of another process into the current process.
+fn duplicate_handle(
+    safe_process_handle: &SafeHandle,
+    handle_value: usize,
+    options: DUPLICATE_HANDLE_OPTIONS,
+) -> windows::core::Result<SafeHandle> {
+    let mut safe_dup_handle = SafeHandle::new(HANDLE::default());
+    unsafe {
+        DuplicateHandle(
+            safe_process_handle.handle,
+            HANDLE(handle_value as isize as *mut c_void),
+            GetCurrentProcess(),
+            &mut safe_dup_handle.handle,
+            0,
+            false,
+            options,
+        )?
+    };
+    Ok(safe_dup_handle)
+}
+
+/// Closes a handle inside the process that owns it.
+///
+/// Pids and handle values are both reused once released, so the owner is checked to still be
+/// `identity` and the handle to still refer to `handle_info.nt_path`.
+pub fn close_remote_handle(
+    identity: &ProcessIdentity,
+    handle_info: &HandleInfo,
+) -> anyhow::Result<()> {
+    if handle_info.is_protected_from_close() {
+        return Err(anyhow!(
+            "Handle 0x{:x} is protected from close",
+            handle_info.handle_value
+        ));
+    }
+
+    let safe_process_handle = identity.open(PROCESS_DUP_HANDLE)?;
+
+    let safe_dup_handle = duplicate_handle(
+        &safe_process_handle,
+        handle_info.handle_value,
+        DUPLICATE_SAME_ACCESS,
+    )?;
+    let nt_path = query_nt_path(safe_dup_handle).ok_or_else(|| {
+        anyhow!(
+            "Timed out resolving the name of handle 0x{:x}, leaving it open",
+            handle_info.handle_value
+        )
+    })??;
+    if !nt_path.eq_ignore_ascii_case(&handle_info.nt_path) {
+        return Err(anyhow!(
+            "Handle 0x{:x} now refers to '{}', leaving it open",
+            handle_info.handle_value,
+            nt_path
+        ));
     }
+
+    // DUPLICATE_CLOSE_SOURCE closes the handle in the owning process, our copy is closed on drop.
+    duplicate_handle(
+        &safe_process_handle,
+        handle_info.handle_value,
+        DUPLICATE_CLOSE_SOURCE | DUPLICATE_SAME_ACCESS,
+    )?;
+    Ok(())
 }
 
 pub fn is_handle_type_file(safe_file_handle: &SafeHandle) -> anyhow::Result<bool> {
@@ -134,12 +442,13 @@ pub fn is_handle_type_file(safe_file_handle: &SafeHandle) -> anyhow::Result<bool
     if object_type_name != "File" {
         return Ok(false);
     }
-    let file_type = unsafe { GetFileType(safe_file_handle.handle) };
-    if file_type != FILE_TYPE_DISK {
-        return Ok(false);
-    }
 
-    Ok(true)
+    Ok(is_disk_file(safe_file_handle))
+}
+
+/// Tells files on disk apart from pipes, consoles and other devices, given a `File` handle.
+fn is_disk_file(safe_file_handle: &SafeHandle) -> bool {
+    unsafe { GetFileType(safe_file_handle.handle) == FILE_TYPE_DISK }
 }
 
 pub fn handle_to_nt_path(safe_file_handle: &SafeHandle) -> anyhow::Result<String> {
@@ -179,17 +488,317 @@ pub struct SystemHandleTableEntryInfoEx {
     reserved: u32,
 }
 
+// SAFETY: `object` is the kernel address of the object, it is never dereferenced.
+unsafe impl Send for SystemHandleTableEntryInfoEx {}
+unsafe impl Sync for SystemHandleTableEntryInfoEx {}
+
 #[cfg(test)]
 mod tests {
     use super::*;
+    use std::time::{Duration, Instant};
+
+    fn handle_entry(pid: u32, handle_value: usize) -> SystemHandleTableEntryInfoEx {
+        SystemHandleTableEntryInfoEx {
+            object: std::ptr::null_mut(),
+            unique_process_id: pid as usize,
+            handle_value,
+            granted_access: 0,
+            creator_back_trace_index: 0,
+            object_type_index: 0,
+            handle_attributes: 0,
+            reserved: 0,
+        }
+    }
+
+    /// A handle table shaped like a busy machine: entries of many processes interleaved.
+    fn synthetic_handle_table(
+        process_count: u32,
+        handles_per_process: usize,
+    ) -> Vec<SystemHandleTableEntryInfoEx> {
+        (0..handles_per_process)
+            .flat_map(|index| {
+                (1..=process_count).map(move |pid| handle_entry(pid * 4, (index + 1) * 4))
+            })
+            .collect()
+    }
+
+    fn fake_resolve(pid: &u32, handle_entry: &SystemHandleTableEntryInfoEx) -> Resolution {
+        // Every fourth handle is a file, and one of them is stuck.
+        if handle_entry.handle_value == 0x40 {
+            Resolution::Unresolved
+        } else if handle_entry.handle_value.is_multiple_of(16) {
+            Resolution::File(HandleInfo {
+                pid: *pid,
+                handle_value: handle_entry.handle_value,
+                ..Default::default()
+            })
+        } else {
+            Resolution::Skipped
+        }
+    }
+
+    fn spin(cost: Duration) {
+        let start = Instant::now();
+        while start.elapsed() < cost {
+            std::hint::spin_loop();
+        }
+    }
+
+    /// Stands in for `OpenProcess`, which costs a few microseconds and a kernel transition.
+    fn fake_open(pid: u32, cost: Duration) -> windows::core::Result<u32> {
+        spin(cost);
+        if pid.is_multiple_of(3) {
+            Err(ERROR_ACCESS_DENIED.into())
+        } else {
+            Ok(pid)
+        }
+    }
+
+    fn jobs(jobs: usize) -> NonZeroUsize {
+        NonZeroUsize::new(jobs).unwrap()
+    }
+
+    /// The file handles found in the system handle table.
+    #[derive(Debug, Default)]
+    struct HandleScan {
+        handles: Vec<HandleInfo>,
+        /// Handles whose name query did not return in time, as `(pid, handle value)`.
+        unresolved: Vec<(u32, usize)>,
+    }
+
+    /// Merges the handles of every process in pid order, whatever order they were resolved in.
+    fn collect_by_pid(batches: impl Iterator<Item = ProcessHandles>) -> HandleScan {
+        let mut batches: Vec<_> = batches.collect();
+        batches.sort_unstable_by_key(|batch| batch.pid);
+
+        let mut handle_scan = HandleScan::default();
+        for batch in batches {
+            handle_scan.handles.extend(batch.handles);
+            handle_scan.unresolved.extend(
+                batch
+                    .unresolved
+                    .into_iter()
+                    .map(|handle_value| (batch.pid, handle_value)),
+            );
+        }
+        handle_scan
+    }
+
+    fn resolve_by_process<P: 'static>(
+        handle_entries: &[SystemHandleTableEntryInfoEx],
+        jobs: NonZeroUsize,
+        open_process: impl Fn(u32) -> windows::core::Result<P> + Send + Sync + 'static,
+        resolve: impl Fn(&P, &SystemHandleTableEntryInfoEx) -> Resolution + Send + Sync + 'static,
+    ) -> HandleScan {
+        collect_by_pid(stream_by_process(
+            handle_entries.to_vec(),
+            jobs,
+            open_process,
+            resolve,
+        ))
+    }
+
+    #[test]
+    fn test_parse_handle_table() {
+        let entries = [handle_entry(4, 0x10), handle_entry(8, 0x20)];
+        let mut buffer = Vec::new();
+        buffer.extend_from_slice(&entries.len().to_ne_bytes());
+        buffer.extend_from_slice(&0usize.to_ne_bytes());
+        for entry in &entries {
+            let bytes = unsafe {
+                std::slice::from_raw_parts(
+                    entry as *const SystemHandleTableEntryInfoEx as *const u8,
+                    std::mem::size_of::<SystemHandleTableEntryInfoEx>(),
+                )
+            };
+            buffer.extend_from_slice(bytes);
+        }
+
+        let parsed = parse_handle_table(&buffer).unwrap();
+        assert_eq!(parsed.len(), 2);
+        assert_eq!(parsed[1].unique_process_id, 8);
+        assert_eq!(parsed[1].handle_value, 0x20);
+
+        // A count that does not fit in the buffer is rejected instead of read out of bounds.
+        buffer.truncate(buffer.len() - 1);
+        assert!(parse_handle_table(&buffer).is_err());
+    }
+
+    #[test]
+    fn test_file_type_index() {
+        let mut probe = handle_entry(100, 0x24);
+        probe.object_type_index = 37;
+        let mut other = handle_entry(200, 0x24);
+        other.object_type_index = 12;
+        let handle_entries = [other, handle_entry(100, 0x20), probe];
+
+        assert_eq!(file_type_index(&handle_entries, 100, 0x24), Some(37));
+        assert_eq!(file_type_index(&handle_entries, 100, 0x28), None);
+    }
+
+    #[test]
+    fn test_resolve_by_process_opens_each_process_once() {
+        let handle_entries = synthetic_handle_table(30, 50);
+        let open_count = Arc::new(AtomicUsize::new(0));
+        let counter = open_count.clone();
+        let handle_scan = resolve_by_process(
+            &handle_entries,
+            jobs(4),
+            move |pid| {
+                counter.fetch_add(1, Ordering::Relaxed);
+                fake_open(pid, Duration::ZERO)
+            },
+            fake_resolve,
+        );
+
+        // Failed opens are remembered too, every pid is tried exactly once.
+        assert_eq!(open_count.load(Ordering::Relaxed), 30);
+        // 20 processes can be opened, 12 of the 50 handles of each are files and one is stuck.
+        assert_eq!(handle_scan.handles.len(), 20 * 11);
+        assert_eq!(handle_scan.unresolved.len(), 20);
+        assert!(
+            handle_scan
+                .handles
+                .iter()
+                .all(|handle_info| !handle_info.pid.is_multiple_of(3))
+        );
+    }
+
+    #[test]
+    fn test_resolve_by_process_is_deterministic() {
+        let mut handle_entries = synthetic_handle_table(40, 30);
+        // A few big processes, so that the batches finish out of order.
+        handle_entries.extend((0..500).map(|index| handle_entry(7, (index + 1) * 16)));
+        let resolve = |pid: &u32, handle_entry: &SystemHandleTableEntryInfoEx| {
+            spin(Duration::from_micros(u64::from(*pid % 5)));
+            fake_resolve(pid, handle_entry)
+        };
+
+        let sequential = resolve_by_process(
+            &handle_entries,
+            jobs(1),
+            |pid| fake_open(pid, Duration::ZERO),
+            resolve,
+        );
+        let key = |handle_info: &HandleInfo| (handle_info.pid, handle_info.handle_value);
+        assert!(
+            sequential
+                .handles
+                .is_sorted_by_key(|handle_info| handle_info.pid)
+        );
+
+        for job_count in [2, 8, 64] {
+            let parallel = resolve_by_process(
+                &handle_entries,
+                jobs(job_count),
+                |pid| fake_open(pid, Duration::ZERO),
+                resolve,
+            );
+            assert_eq!(
+                parallel.handles.iter().map(key).collect::<Vec<_>>(),
+                sequential.handles.iter().map(key).collect::<Vec<_>>(),
+                "{job_count} jobs"
+            );
+            assert_eq!(
+                parallel.unresolved, sequential.unresolved,
+                "{job_count} jobs"
+            );
+        }
+    }
+
+    #[test]
+    fn test_stream_by_process_stops_when_dropped() {
+        let handle_entries = synthetic_handle_table(30, 50);
+        let open_count = Arc::new(AtomicUsize::new(0));
+        let counter = open_count.clone();
+        let mut stream = stream_by_process(
+            handle_entries,
+            jobs(1),
+            move |pid| {
+                counter.fetch_add(1, Ordering::Relaxed);
+                fake_open(pid, Duration::ZERO)
+            },
+            |pid: &u32, handle_entry: &SystemHandleTableEntryInfoEx| {
+                spin(Duration::from_millis(1));
+                fake_resolve(pid, handle_entry)
+            },
+        );
+
+        assert!(stream.next().is_some());
+        drop(stream);
+        assert!(open_count.load(Ordering::Relaxed) < 30);
+    }
+
+    #[test]
+    fn test_resolve_by_process_empty_table() {
+        let handle_scan = resolve_by_process(
+            &[],
+            jobs(8),
+            |pid| fake_open(pid, Duration::ZERO),
+            fake_resolve,
+        );
+        assert!(handle_scan.handles.is_empty());
+        assert!(handle_scan.unresolved.is_empty());
+    }
+
+    // cargo test bench_resolve_by_process --release -- --ignored --nocapture
+    #[test]
+    #[ignore]
+    fn bench_resolve_by_process() {
+        const OPEN_COST: Duration = Duration::from_micros(5);
+        // DuplicateHandle followed by NtQueryObject.
+        const RESOLVE_COST: Duration = Duration::from_micros(2);
+        let handle_entries = synthetic_handle_table(500, 400);
+        let resolve = |pid: &u32, handle_entry: &SystemHandleTableEntryInfoEx| {
+            spin(RESOLVE_COST);
+            fake_resolve(pid, handle_entry)
+        };
+
+        // What enumeration used to do: open the owning process for every entry.
+        let start = Instant::now();
+        let mut per_entry = Vec::new();
+        for handle_entry in &handle_entries {
+            if let Ok(pid) = fake_open(handle_entry.unique_process_id as u32, OPEN_COST)
+                && let Resolution::File(handle_info) = resolve(&pid, handle_entry)
+            {
+                per_entry.push(handle_info);
+            }
+        }
+        let per_entry_elapsed = start.elapsed();
+        println!(
+            "{} entries: open per entry {per_entry_elapsed:?}",
+            handle_entries.len()
+        );
+
+        let cpus = std::thread::available_parallelism().unwrap_or(NonZeroUsize::MIN);
+        for job_count in [NonZeroUsize::MIN, cpus] {
+            let start = Instant::now();
+            let grouped = resolve_by_process(
+                &handle_entries,
+                job_count,
+                |pid| fake_open(pid, OPEN_COST),
+                resolve,
+            );
+            let grouped_elapsed = start.elapsed();
+
+            assert_eq!(per_entry.len(), grouped.handles.len());
+            println!(
+                "{} entries: open per process, {job_count} job(s) {grouped_elapsed:?} ({:.0}x)",
+                handle_entries.len(),
+                per_entry_elapsed.as_secs_f64() / grouped_elapsed.as_secs_f64()
+            );
+        }
+    }
 
     // cargo test test_enum_handles -- --nocapture
     #[test]
     fn test_enum_handles() {
-        let handle_infos = enum_handles().unwrap();
-        assert!(!handle_infos.is_empty());
+        let handle_scan = collect_by_pid(
+            stream_handles(NonZeroUsize::MIN, nt_ext::DEFAULT_MAX_BUFFER, |_| true).unwrap(),
+        );
+        assert!(!handle_scan.handles.is_empty());
 
-        for handle_info in handle_infos {
+        for handle_info in handle_scan.handles {
             println!("pid={}, nt_path={}", handle_info.pid, handle_info.nt_path);
         }
     }
@@ -0,0 +1,587 @@
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::io::{self, IsTerminal, Write};
+use std::time::Duration;
+
+use anyhow::Context;
+use colored::Colorize;
+
+use crate::audit::{AuditAction, AuditLog};
+use crate::process_ext::{self, ProcessTable, TerminationStep};
+use crate::process_tree::{self, TreeEntry};
+use crate::protect::Denylist;
+use crate::{Cli, ProcessResult, ScanResult, find_locker};
+
+/// Answer to the per-locker prompt of `--interactive`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Answer {
+    Yes,
+    No,
+    All,
+    Quit,
+}
+
+pub fn parse_answer(input: &str) -> Option<Answer> {
+    match input.trim().to_ascii_lowercase().as_str() {
+        "y" | "yes" => Some(Answer::Yes),
+        "n" | "no" => Some(Answer::No),
+        "a" | "all" => Some(Answer::All),
+        "q" | "quit" => Some(Answer::Quit),
+        _ => None,
+    }
+}
+
+/// Picks the lockers chosen by `--kill-pid` and `--kill-name`, or all of them if neither is given.
+///
+/// Returns the selected lockers and the requested pids that are not locking the path.
+pub fn select_targets<'a>(
+    lockers: &'a BTreeMap<u32, ProcessResult>,
+    kill_pids: &[u32],
+    kill_names: &[String],
+) -> (Vec<&'a ProcessResult>, Vec<u32>) {
+    if kill_pids.is_empty() && kill_names.is_empty() {
+        return (lockers.values().collect(), Vec::new());
+    }
+
+    let targets = lockers
+        .values()
+        .filter(|locker| {
+            kill_pids.contains(&locker.pid)
+                || kill_names
+                    .iter()
+                    .any(|name| name.eq_ignore_ascii_case(&locker.name))
+        })
+        .collect();
+
+    let unknown_pids = kill_pids
+        .iter()
+        .copied()
+        .filter(|pid| !lockers.contains_key(pid))
+        .collect();
+
+    (targets, unknown_pids)
+}
+
+pub fn confirm_and_kill(cli: &Cli, scan: &ScanResult) -> anyhow::Result<()> {
+    let lockers = &scan.lockers;
+    let (targets, unknown_pids) = select_targets(lockers, &cli.kill_pid, &cli.kill_name);
+    for pid in unknown_pids {
+        eprintln!("Warning: PID {pid} is not locking the path, ignored.");
+    }
+
+    if targets.is_empty() {
+        println!("No locker matches the given --kill-pid/--kill-name, nothing to kill.");
+        return Ok(());
+    }
+
+    // Protected lockers are dropped before the walk, so that none of them roots a tree.
+    let denylist = Denylist::new(&cli.protect).with_ancestors(scan.processes.as_slice());
+    let (targets, mut skipped) = if cli.allow_protected {
+        (targets, Vec::new())
+    } else {
+        exclude_protected(targets, &denylist)
+    };
+
+    let tree = if cli.tree {
+        let roots: Vec<u32> = targets.iter().map(|target| target.pid).collect();
+        process_tree::walk_trees(scan.processes.as_slice(), &roots)
+    } else {
+        Vec::new()
+    };
+    let descendants = tree_descendants(&tree, lockers, &scan.processes);
+    let targets = if cli.tree {
+        let tree = prune_protected(
+            &tree,
+            lockers,
+            &descendants,
+            (!cli.allow_protected).then_some(&denylist),
+            scan.processes.as_slice(),
+            &mut skipped,
+        );
+        print_tree(&tree, lockers, &descendants);
+        // Children go first, so that they cannot keep the file open once their parent is gone.
+        tree.iter()
+            .rev()
+            .filter_map(|entry| {
+                lockers
+                    .get(&entry.pid)
+                    .or_else(|| descendants.get(&entry.pid))
+            })
+            .collect()
+    } else {
+        targets
+    };
+
+    if cli.dry_run {
+        print_dry_run(&targets);
+        print_skipped(&skipped);
+        print_remaining(lockers, &targets);
+        return Ok(());
+    }
+
+    print_skipped(&skipped);
+    if targets.is_empty() {
+        println!("Every selected locker is protected, nothing to kill.");
+        return Ok(());
+    }
+
+    let mut audit_log = AuditLog::open(cli.audit_log.as_deref(), &cli.audit_target())?;
+
+    let targets = if cli.interactive {
+        require_terminal("kill", cli.yes)?;
+        match confirm_each(targets)? {
+            Some(targets) => targets,
+            None => {
+                println!("Operation cancelled by user.");
+                return Ok(());
+            }
+        }
+    } else if confirm("kill", cli.yes, || {
+        println!(
+            "{}",
+            "WARNING: You are about to attempt to KILL the process(es) listed above."
+                .bold()
+                .yellow()
+        );
+    })? {
+        targets
+    } else {
+        return Ok(());
+    };
+
+    if targets.is_empty() {
+        println!("No processes were selected, nothing to kill.");
+        print_remaining(lockers, &targets);
+        return Ok(());
+    }
+
+    println!("Proceeding to kill processes...");
+    let killed_count = kill_processes(
+        &targets,
+        Duration::from_secs(cli.grace_period),
+        Duration::from_secs(cli.exit_timeout),
+        &mut audit_log,
+    );
+    println!(
+        "Terminated {killed_count} of {} process(es).",
+        targets.len()
+    );
+    if killed_count < targets.len() {
+        println!(
+            "{}",
+            "Note: Some processes might not have been killed due to errors, lack of permissions, or if they are still shutting down."
+            .yellow()
+        );
+    }
+    print_remaining(lockers, &targets);
+    verify_released(cli, lockers)
+}
+
+/// Drops every protected process of the `--tree` walk along with its whole subtree.
+///
+/// Locksmith and its ancestor chain are always dropped, even with `--allow-protected`, since
+/// killing them would kill locksmith halfway through the tree. The other processes are only
+/// checked against `denylist` when one is given.
+fn prune_protected<'a>(
+    tree: &[TreeEntry],
+    lockers: &'a BTreeMap<u32, ProcessResult>,
+    descendants: &'a HashMap<u32, ProcessResult>,
+    denylist: Option<&Denylist>,
+    processes: &[process_ext::ProcessInfo],
+    skipped: &mut Vec<(&'a ProcessResult, String)>,
+) -> Vec<TreeEntry> {
+    let self_pid = std::process::id();
+    let own_chain: HashSet<u32> = process_tree::ancestors(processes, self_pid)
+        .into_iter()
+        .chain([self_pid])
+        .collect();
+
+    process_tree::prune_subtrees(tree, |entry| {
+        let process = lockers
+            .get(&entry.pid)
+            .or_else(|| descendants.get(&entry.pid));
+        if skipped.iter().any(|(skipped, _)| skipped.pid == entry.pid) {
+            return true;
+        }
+        let reason = if own_chain.contains(&entry.pid) {
+            Some("this is locksmith or one of its ancestors".to_string())
+        } else {
+            denylist
+                .zip(process)
+                .and_then(|(denylist, process)| denylist.check(process))
+        };
+        match (reason, process) {
+            (Some(reason), Some(process)) => {
+                skipped.push((process, reason));
+                true
+            }
+            (reason, None) => reason.is_some(),
+            (None, Some(_)) => false,
+        }
+    })
+}
+
+/// Describes the processes of the `--tree` walk that are not lockers themselves.
+fn tree_descendants(
+    tree: &[TreeEntry],
+    lockers: &BTreeMap<u32, ProcessResult>,
+    process_table: &ProcessTable,
+) -> HashMap<u32, ProcessResult> {
+    let pids: HashSet<u32> = tree
+        .iter()
+        .map(|entry| entry.pid)
+        .filter(|pid| !lockers.contains_key(pid))
+        .collect();
+
+    process_table
+        .as_slice()
+        .iter()
+        .filter(|process| pids.contains(&process.pid))
+        .map(|process| {
+            let descendant = ProcessResult::describe(process_table, &process.identity());
+            (process.pid, descendant)
+        })
+        .collect()
+}
+
+fn print_tree(
+    tree: &[TreeEntry],
+    lockers: &BTreeMap<u32, ProcessResult>,
+    descendants: &HashMap<u32, ProcessResult>,
+) {
+    println!("Planned process tree, children are killed before their parents:");
+    for entry in tree {
+        let indent = "  ".repeat(entry.depth + 1);
+        match (lockers.get(&entry.pid), descendants.get(&entry.pid)) {
+            (Some(locker), _) => println!(
+                "{indent}PID {}, Name: '{}' (locker)",
+                locker.pid, locker.name
+            ),
+            (None, Some(descendant)) => println!(
+                "{indent}PID {}, Name: '{}'",
+                descendant.pid, descendant.name
+            ),
+            (None, None) => {}
+        }
+    }
+}
+
+/// Splits off the targets that the denylist refuses to kill, along with the reason.
+pub fn exclude_protected<'a>(
+    targets: Vec<&'a ProcessResult>,
+    denylist: &Denylist,
+) -> (Vec<&'a ProcessResult>, Vec<(&'a ProcessResult, String)>) {
+    let mut allowed = Vec::with_capacity(targets.len());
+    let mut skipped = Vec::new();
+    for target in targets {
+        match denylist.check(target) {
+            Some(reason) => skipped.push((target, reason)),
+            None => allowed.push(target),
+        }
+    }
+    (allowed, skipped)
+}
+
+pub fn print_skipped(skipped: &[(&ProcessResult, String)]) {
+    if skipped.is_empty() {
+        return;
+    }
+
+    println!(
+        "{}",
+        "The following protected process(es) will be left alone (override with --allow-protected):"
+            .yellow()
+    );
+    for (locker, reason) in skipped {
+        println!(
+            "  PID {}, Name: '{}', Path: '{}': {}",
+            locker.pid,
+            locker.name,
+            locker.display_path(),
+            reason
+        );
+    }
+}
+
+fn print_warning() {
+    println!(
+        "{}",
+        "This is a DESTRUCTIVE and UNRECOVERABLE operation that could lead to data loss or system instability."
+            .bold()
+            .red()
+    );
+}
+
+/// Reads one line of input, failing once stdin is closed, so that a caller that asks again on
+/// an unexpected answer does not loop forever.
+fn prompt(message: &str) -> anyhow::Result<String> {
+    print!("{} ", message.bold().yellow());
+    io::stdout()
+        .flush()
+        .context("Failed to flush stdout")
+        .unwrap_or_else(|e| eprintln!("Error flushing stdout: {e:#}"));
+
+    let mut input = String::new();
+    let read = io::stdin()
+        .read_line(&mut input)
+        .context("Failed to read user input, operation cancelled")?;
+    if read == 0 {
+        println!();
+        return Err(anyhow::anyhow!("stdin was closed, operation cancelled"));
+    }
+    Ok(input)
+}
+
+/// Asks once before a destructive `action`, unless `yes` was passed.
+///
+/// `describe` prints what is about to happen before the prompt. Returns `false` if the user
+/// declined, fails if there is no terminal to ask on.
+pub fn confirm(action: &str, yes: bool, describe: impl FnOnce()) -> anyhow::Result<bool> {
+    if yes {
+        return Ok(true);
+    }
+    require_terminal(action, yes)?;
+
+    describe();
+    print_warning();
+    let confirmation = prompt("Are you absolutely sure you want to proceed? (y/N):")?;
+    if confirmation.trim().eq_ignore_ascii_case("y") {
+        Ok(true)
+    } else {
+        println!("Operation cancelled by user.");
+        Ok(false)
+    }
+}
+
+/// Fails if the user has to be asked but stdin is not a terminal.
+fn require_terminal(action: &str, yes: bool) -> anyhow::Result<()> {
+    if !yes && !io::stdin().is_terminal() {
+        return Err(anyhow::anyhow!(
+            "Refusing to {action} without confirmation: stdin is not a terminal, pass --yes to proceed"
+        ));
+    }
+    Ok(())
+}
+
+/// Asks about each target in turn, returns `None` if the user quit before choosing any.
+fn confirm_each(targets: Vec<&ProcessResult>) -> anyhow::Result<Option<Vec<&ProcessResult>>> {
+    println!(
+        "{}",
+        "WARNING: You are about to choose process(es) to KILL one by one."
+            .bold()
+            .yellow()
+    );
+    print_warning();
+    println!(
+        "Answer y(es), n(o), a(ll: this one and all remaining) or q(uit: none of the remaining)."
+    );
+
+    let mut selected = Vec::with_capacity(targets.len());
+    let mut targets = targets.into_iter();
+    while let Some(target) = targets.next() {
+        let question = format!(
+            "Kill PID {}, Name: '{}', Path: '{}'? (y/n/a/q):",
+            target.pid,
+            target.name,
+            target.display_path()
+        );
+        loop {
+            match parse_answer(&prompt(&question)?) {
+                Some(Answer::Yes) => selected.push(target),
+                Some(Answer::No) => {}
+                Some(Answer::All) => {
+                    selected.push(target);
+                    selected.extend(targets.by_ref());
+                }
+                Some(Answer::Quit) if selected.is_empty() => return Ok(None),
+                Some(Answer::Quit) => return Ok(Some(selected)),
+                None => {
+                    println!("Please answer y, n, a or q.");
+                    continue;
+                }
+            }
+            break;
+        }
+    }
+
+    Ok(Some(selected))
+}
+
+fn print_dry_run(targets: &[&ProcessResult]) {
+    println!("Dry run, no process will be terminated.");
+    println!("The following process(es) would be killed, in this order:");
+    for (index, target) in targets.iter().enumerate() {
+        println!(
+            "  {}. PID {}, Name: '{}', Path: '{}'",
+            index + 1,
+            target.pid,
+            target.name,
+            target.display_path()
+        );
+    }
+}
+
+/// Reports the lockers that were left alone.
+fn print_remaining(lockers: &BTreeMap<u32, ProcessResult>, targets: &[&ProcessResult]) {
+    let remaining: Vec<_> = lockers
+        .values()
+        .filter(|locker| !targets.iter().any(|target| target.pid == locker.pid))
+        .collect();
+    if remaining.is_empty() {
+        return;
+    }
+
+    println!("The following locker(s) were not selected and are left running:");
+    for locker in remaining {
+        println!(
+            "  PID {}, Name: '{}', Path: '{}'",
+            locker.pid,
+            locker.name,
+            locker.display_path()
+        );
+    }
+}
+
+/// Terminates the targets, returns how many of them are confirmed to have exited.
+pub fn kill_processes(
+    targets: &[&ProcessResult],
+    grace_period: Duration,
+    exit_timeout: Duration,
+    audit_log: &mut AuditLog,
+) -> usize {
+    let mut killed_count = 0;
+
+    println!(
+        "{}",
+        "IMPORTANT: Attempting to terminate processes. This can have unintended consequences."
+            .bold()
+            .red()
+    );
+
+    for process_info in targets {
+        let pid = process_info.pid;
+        println!(
+            "Attempting to kill process: PID {}, Name: '{}', Path: '{}'",
+            process_info.pid,
+            process_info.name,
+            process_info.display_path()
+        );
+        let result =
+            process_ext::terminate_process(&process_info.identity(), grace_period, exit_timeout)
+                .and_then(|outcome| match outcome.exit_code {
+                    Some(exit_code) => Ok((outcome.step, exit_code)),
+                    None => Err(anyhow::anyhow!(
+                        "Process was told to terminate but is still running after {}s",
+                        exit_timeout.as_secs()
+                    )),
+                });
+        audit_log.record(process_info, AuditAction::Kill, None, &result);
+
+        match result {
+            Ok((TerminationStep::CloseRequest, exit_code)) => {
+                println!(
+                    "Process PID {pid} exited with code {exit_code} after being asked to close its windows."
+                );
+                killed_count += 1;
+            }
+            Ok((TerminationStep::HardKill, exit_code)) => {
+                println!("Process PID {pid} was forcefully terminated, exit code {exit_code}.");
+                killed_count += 1;
+            }
+            Err(e) => {
+                eprintln!("Failed to kill process PID {pid}: {e:#}");
+                // Ignore the error and continue
+            }
+        }
+    }
+    killed_count
+}
+
+/// Scans the path again and reports whether it is free, including lockers that were not
+/// there before.
+pub fn verify_released(cli: &Cli, previous: &BTreeMap<u32, ProcessResult>) -> anyhow::Result<()> {
+    let rescan = find_locker(&cli.paths, &cli.scan).context("Failed to scan the path again")?;
+    for err in &rescan.errors {
+        eprintln!("Warning: {err:#}");
+    }
+
+    if rescan.lockers.is_empty() {
+        if rescan.errors.is_empty() {
+            println!("{}", "The path is no longer locked.".green());
+        } else {
+            println!("No locker found, but the re-scan was partial.");
+        }
+        return Ok(());
+    }
+
+    println!(
+        "{}",
+        "The path is still locked by the following process(es):".yellow()
+    );
+    for locker in rescan.lockers.values() {
+        let new = if previous
+            .get(&locker.pid)
+            .is_some_and(|previous| previous.create_time == locker.create_time)
+        {
+            ""
+        } else {
+            " (new)"
+        };
+        println!(
+            "  PID {}, Name: '{}', Path: '{}'{new}",
+            locker.pid,
+            locker.name,
+            locker.display_path()
+        );
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn lockers() -> BTreeMap<u32, ProcessResult> {
+        [(10, "notepad.exe"), (20, "explorer.exe"), (30, "Code.exe")]
+            .into_iter()
+            .map(|(pid, name)| {
+                let locker = ProcessResult {
+                    pid,
+                    name: name.to_string(),
+                    path: Some(format!(r"C:\Windows\{name}")),
+                    ..Default::default()
+                };
+                (pid, locker)
+            })
+            .collect()
+    }
+
+    fn pids(targets: &[&ProcessResult]) -> Vec<u32> {
+        targets.iter().map(|target| target.pid).collect()
+    }
+
+    #[test]
+    fn test_select_targets_defaults_to_all() {
+        let lockers = lockers();
+        let (targets, unknown) = select_targets(&lockers, &[], &[]);
+        assert_eq!(pids(&targets), vec![10, 20, 30]);
+        assert!(unknown.is_empty());
+    }
+
+    #[test]
+    fn test_select_targets_by_pid_and_name() {
+        let lockers = lockers();
+        let (targets, unknown) = select_targets(&lockers, &[30, 99], &["NOTEPAD.EXE".to_string()]);
+        assert_eq!(pids(&targets), vec![10, 30]);
+        assert_eq!(unknown, vec![99]);
+    }
+
+    #[test]
+    fn test_parse_answer() {
+        assert_eq!(parse_answer("y\n"), Some(Answer::Yes));
+        assert_eq!(parse_answer(" No "), Some(Answer::No));
+        assert_eq!(parse_answer("A"), Some(Answer::All));
+        assert_eq!(parse_answer("quit"), Some(Answer::Quit));
+        assert_eq!(parse_answer(""), None);
+        assert_eq!(parse_answer("maybe"), None);
+    }
+}
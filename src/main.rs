@@ -1,211 +1,777 @@
 use anyhow::Context;
-use clap::Parser;
-use colored::Colorize;
-use std::collections::HashMap;
-use std::io::{self, Write};
-use std::path::Path;
+use clap::error::ErrorKind;
+use clap::parser::ValueSource;
+use clap::{ArgGroup, Args, CommandFactory, FromArgMatches, Parser, Subcommand, ValueEnum};
+use serde::Serialize;
+use std::cell::RefCell;
+use std::cmp::Reverse;
+use std::collections::{BTreeMap, HashMap};
+use std::num::NonZeroUsize;
+use std::path::{Path, PathBuf};
+use std::process::ExitCode;
 use std::time::Instant;
 
+use process_ext::{ProcessIdentity, ProcessTable};
+
+mod audit;
+mod color;
+mod filter;
+mod freeze;
 mod handle_ext;
+mod kill;
 mod nt_ext;
 mod path_ext;
+mod plan;
 mod process_ext;
+mod process_tree;
+mod protect;
 mod safe_handle;
 mod string_ext;
+mod unlock;
+mod watchdog;
+
+/// No process is locking the path.
+const EXIT_NO_LOCKER: u8 = 0;
+/// At least one process is locking the path.
+const EXIT_LOCKERS_FOUND: u8 = 1;
+/// Some lockers may be missing because part of the scan failed.
+const EXIT_PARTIAL_SCAN: u8 = 2;
+/// The scan could not be performed at all.
+const EXIT_ERROR: u8 = 3;
 
 #[derive(Parser, Debug)]
 #[command(name = "locksmith")]
 #[command(author = "Fu Wang <wangfu91@hotmail.com>")]
 #[command(
     about = "locksmith - Find processes locking your files",
-    long_about = "A Windows utility to find out which processes are using your files"
+    long_about = "A Windows utility to find out which processes are using your files",
+    after_long_help = "Exit codes:\n  \
+        0  No locker found\n  \
+        1  One or more lockers found\n  \
+        2  Partial scan, some lockers may be missing\n  \
+        3  Error, the scan could not be performed"
 )]
+#[command(group(ArgGroup::new("kill_action").args(["kill", "kill_pid", "kill_name"]).multiple(true)))]
+#[command(group(ArgGroup::new("action").args(["kill", "kill_pid", "kill_name", "unlock"]).multiple(true)))]
+#[command(group(ArgGroup::new("targets").args(["paths", "paths_from"]).multiple(true).required(true)))]
+#[command(subcommand_negates_reqs = true)]
 struct Cli {
-    /// Path to the file you want to check for locks
-    #[arg(required = true)]
-    path: String,
+    #[command(subcommand)]
+    command: Option<Command>,
+
+    /// Paths to the files you want to check for locks, a process locking any of them is
+    /// reported
+    #[arg(value_name = "PATH")]
+    paths: Vec<String>,
 
-    /// Forcefully kill the processes locking the file (requires confirmation)
+    /// Also check the paths listed in this file, one per line
+    #[arg(long, value_name = "FILE")]
+    paths_from: Option<PathBuf>,
+
+    /// Kill the processes locking the file, see --grace-period (requires confirmation)
     #[arg(short = 'k', long, default_value_t = false)]
     kill: bool,
+
+    /// Kill only the locker with this pid (can be repeated)
+    #[arg(long, value_name = "PID")]
+    kill_pid: Vec<u32>,
+
+    /// Kill only the lockers with this image name, e.g. notepad.exe (can be repeated)
+    #[arg(long, value_name = "IMAGE")]
+    kill_name: Vec<String>,
+
+    /// Close the handles to the file inside the processes holding them instead of killing
+    /// the processes (requires confirmation)
+    #[arg(long, default_value_t = false, conflicts_with = "kill_action")]
+    unlock: bool,
+
+    /// Ask for confirmation before killing each locker
+    #[arg(
+        short = 'i',
+        long,
+        default_value_t = false,
+        requires = "kill_action",
+        conflicts_with = "yes"
+    )]
+    interactive: bool,
+
+    /// Seconds to wait for a locker to exit after asking its windows to close before
+    /// terminating it forcefully, 0 terminates it forcefully right away
+    #[arg(
+        long,
+        value_name = "SECONDS",
+        default_value_t = 5,
+        requires = "kill_action"
+    )]
+    grace_period: u64,
+
+    /// Seconds to wait for a locker to exit once it has been terminated
+    #[arg(
+        long,
+        value_name = "SECONDS",
+        default_value_t = 10,
+        requires = "kill_action"
+    )]
+    exit_timeout: u64,
+
+    /// Also kill the descendants of each locker, children before their parents
+    #[arg(long, default_value_t = false, requires = "kill_action")]
+    tree: bool,
+
+    /// Never kill or unlock this process, given as a pid, an image name or an image path
+    /// (can be repeated)
+    #[arg(long, value_name = "PID|NAME|PATH", requires = "action")]
+    protect: Vec<protect::ProtectRule>,
+
+    /// Also kill or unlock protected processes such as csrss.exe, the System process or
+    /// locksmith's parent
+    #[arg(long, default_value_t = false, requires = "action")]
+    allow_protected: bool,
+
+    /// Skip the confirmation prompt, required when stdin is not a terminal
+    #[arg(short = 'y', long, default_value_t = false, requires = "action")]
+    yes: bool,
+
+    /// Print which processes --kill would terminate, or which handles --unlock would close,
+    /// without touching any of them
+    #[arg(long, default_value_t = false, requires = "action")]
+    dry_run: bool,
+
+    /// Print nothing on stdout, only report the result through the exit code
+    #[arg(
+        short = 'q',
+        long,
+        default_value_t = false,
+        conflicts_with_all = ["pids_only", "action"]
+    )]
+    quiet: bool,
+
+    /// Print only the pid of each locker, one per line
+    #[arg(long, default_value_t = false, conflicts_with = "action")]
+    pids_only: bool,
+
+    /// Print the lockers, their owners and their handles as JSON
+    #[arg(
+        long,
+        default_value_t = false,
+        conflicts_with_all = ["quiet", "pids_only", "action"]
+    )]
+    json: bool,
+
+    /// Order in which the lockers are printed
+    #[arg(long, value_enum, default_value_t = LockerOrder::Pid)]
+    sort: LockerOrder,
+
+    /// Stop at the first locker found, for checks that only need to know whether the path is
+    /// locked
+    #[arg(long, default_value_t = false, conflicts_with = "action")]
+    first: bool,
+
+    #[command(flatten)]
+    scan: ScanOptions,
+
+    /// Append a JSON line to this file for every kill, unlock, suspend and resume
+    #[arg(long, value_name = "PATH", env = "LOCKSMITH_AUDIT_LOG", global = true)]
+    audit_log: Option<PathBuf>,
+
+    /// When to use colored output
+    #[arg(long, value_enum, default_value_t = color::ColorChoice::Auto, global = true)]
+    color: color::ColorChoice,
+}
+
+impl Cli {
+    /// Parses the command line, exiting on a top-level option given along with a subcommand.
+    ///
+    /// Global options, such as the scan options, `--audit-log` and `--color`, are shared with
+    /// the subcommands and may come before them. The other top-level options only apply to a
+    /// scan without a subcommand, and clap alone would silently ignore them.
+    fn parse_checked() -> Self {
+        let matches = Self::command().get_matches();
+        let cli = Self::from_arg_matches(&matches).unwrap_or_else(|err| err.exit());
+        if cli.command.is_none() {
+            return cli;
+        }
+
+        let mut command = Self::command();
+        let stray = command
+            .get_arguments()
+            .filter(|arg| !arg.is_global_set())
+            .find(|arg| {
+                matches.value_source(arg.get_id().as_str()) == Some(ValueSource::CommandLine)
+            })
+            .map(|arg| match arg.get_long() {
+                Some(long) => format!("--{long}"),
+                None => format!("<{}>", arg.get_id()),
+            });
+        if let Some(stray) = stray {
+            command
+                .error(
+                    ErrorKind::ArgumentConflict,
+                    format!("the argument '{stray}' cannot be used with a subcommand"),
+                )
+                .exit();
+        }
+        cli
+    }
+
+    /// Appends the paths listed in the `--paths-from` file to the paths given on the command
+    /// line. Blank lines are skipped.
+    fn read_paths_from(&mut self) -> anyhow::Result<()> {
+        let Some(paths_from) = &self.paths_from else {
+            return Ok(());
+        };
+        let contents = std::fs::read_to_string(paths_from)
+            .with_context(|| format!("Failed to read '{}'", paths_from.display()))?;
+        self.paths.extend(
+            contents
+                .lines()
+                .map(str::trim)
+                .filter(|line| !line.is_empty())
+                .map(str::to_string),
+        );
+        if self.paths.is_empty() {
+            return Err(anyhow::anyhow!(
+                "'{}' does not list any path",
+                paths_from.display()
+            ));
+        }
+        Ok(())
+    }
+
+    /// The scanned paths as recorded in the audit log, separated by `|`, which cannot appear
+    /// in a Windows path.
+    fn audit_target(&self) -> String {
+        self.paths.join("|")
+    }
+}
+
+/// Options that tune how the lockers are searched for, shared by every command.
+#[derive(Args, Debug, Clone, Default)]
+struct ScanOptions {
+    /// Number of threads resolving handles [default: number of logical CPUs]
+    #[arg(long, value_name = "N", global = true)]
+    jobs: Option<NonZeroUsize>,
+
+    /// Do not look for processes that have the path loaded as a module
+    #[arg(
+        long,
+        default_value_t = false,
+        global = true,
+        conflicts_with = "modules"
+    )]
+    no_modules: bool,
+
+    /// Look for processes that have the path loaded as a module even if it is not a directory
+    /// or an executable image, which are the only paths checked by default
+    #[arg(long, default_value_t = false, global = true)]
+    modules: bool,
+
+    /// Only look at these processes (can be repeated)
+    #[arg(long = "pid", value_name = "PID", global = true)]
+    pids: Vec<u32>,
+
+    /// Only look at the processes whose image name matches this glob, e.g. "code*" (can be
+    /// repeated)
+    #[arg(long = "name", value_name = "GLOB", global = true)]
+    names: Vec<String>,
+
+    /// Only look at the processes whose image path matches this glob, e.g.
+    /// "C:\Program Files\*" (can be repeated)
+    #[arg(long = "image", value_name = "GLOB", global = true)]
+    images: Vec<String>,
+
+    /// Only look at the processes running as this user, given as a user name or as
+    /// DOMAIN\user (can be repeated)
+    #[arg(long = "user", value_name = "USER", global = true)]
+    users: Vec<String>,
+
+    /// Ignore the processes whose image name matches this glob (can be repeated)
+    #[arg(long = "exclude-name", value_name = "GLOB", global = true)]
+    exclude_names: Vec<String>,
+
+    /// Only look at the processes running as the current user
+    #[arg(long, default_value_t = false, global = true, conflicts_with = "users")]
+    mine: bool,
+
+    /// Largest buffer, in MiB, to read the handle table or the process list into [default: 1024]
+    #[arg(long, value_name = "MIB", global = true)]
+    max_buffer: Option<NonZeroUsize>,
+}
+
+impl ScanOptions {
+    fn jobs(&self) -> NonZeroUsize {
+        self.jobs
+            .unwrap_or_else(|| std::thread::available_parallelism().unwrap_or(NonZeroUsize::MIN))
+    }
+
+    /// Builds the filter the lockers have to pass.
+    fn filter(&self) -> anyhow::Result<filter::ProcessFilter> {
+        let process_filter = filter::ProcessFilter {
+            pids: self.pids.clone(),
+            names: self.names.clone(),
+            images: self.images.clone(),
+            users: self.users.clone(),
+            exclude_names: self.exclude_names.clone(),
+        };
+        if self.mine {
+            process_filter.with_current_user()
+        } else {
+            Ok(process_filter)
+        }
+    }
+
+    /// Returns the cap on the buffers of the system queries, in bytes.
+    fn max_buffer(&self) -> usize {
+        self.max_buffer
+            .map_or(nt_ext::DEFAULT_MAX_BUFFER, |max_buffer| {
+                max_buffer.get().saturating_mul(1024 * 1024)
+            })
+    }
+
+    /// Whether the loaded modules of every process have to be checked against the paths.
+    fn scan_modules(&self, reference_paths: &[String]) -> bool {
+        if self.no_modules {
+            false
+        } else {
+            self.modules
+                || reference_paths
+                    .iter()
+                    .any(|reference_path| path_ext::may_be_image(Path::new(reference_path)))
+        }
+    }
+}
+
+/// Order in which the lockers are printed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+enum LockerOrder {
+    /// By pid
+    Pid,
+    /// Oldest process first
+    StartTime,
+    /// Most open handles first
+    Handles,
 }
 
-fn main() {
+#[derive(Subcommand, Debug)]
+enum Command {
+    /// Suspend the processes locking a file while a command runs, then resume them
+    Freeze(freeze::FreezeArgs),
+    /// Write the actions to take on the processes locking a file to a plan file
+    Plan(plan::PlanArgs),
+    /// Re-validate a plan against the running processes and execute what still holds
+    Apply(plan::ApplyArgs),
+}
+
+fn main() -> ExitCode {
     let start = Instant::now();
-    let cli = Cli::parse();
-    let find_result = find_locker(&cli);
+    let mut cli = Cli::parse_checked();
+    color::init(cli.color);
+
+    match &cli.command {
+        Some(Command::Freeze(args)) => {
+            return freeze::run(args, &cli.scan, cli.audit_log.as_deref());
+        }
+        Some(Command::Plan(args)) => return plan::run_plan(args, &cli.scan),
+        Some(Command::Apply(args)) => {
+            return plan::run_apply(args, &cli.scan, cli.audit_log.as_deref());
+        }
+        None => {}
+    }
+
+    if let Err(err) = cli.read_paths_from() {
+        eprintln!("Error: {err:#}");
+        return ExitCode::from(EXIT_ERROR);
+    }
+
+    let find_result = if cli.first {
+        find_first_locker(&cli.paths, &cli.scan)
+    } else {
+        find_locker(&cli.paths, &cli.scan)
+    };
     let elapsed = start.elapsed();
 
-    match find_result {
-        Ok(results) => {
-            if results.is_empty() {
-                eprintln!("No locker found");
-            } else {
-                println!(
-                    "Found {} locker(s) in {:.2}s:\n",
-                    results.len(),
-                    elapsed.as_secs_f64()
-                );
-                for result in results.values() {
-                    println!("pid: {}", result.pid);
-                    println!("name: {}", result.name);
-                    println!("path: {}", result.path);
-                    println!();
-                }
+    let scan = match find_result {
+        Ok(scan) => scan,
+        Err(err) => {
+            eprintln!("Error: {err:#}");
+            return ExitCode::from(EXIT_ERROR);
+        }
+    };
 
-                if cli.kill {
-                    println!(
-                        "{}",
-                        "WARNING: You are about to attempt to KILL the process(es) listed above."
-                            .bold()
-                            .yellow()
-                    );
-                    println!(
-                        "{}",
-                        "This is a DESTRUCTIVE and UNRECOVERABLE operation that could lead to data loss or system instability."
-                            .bold()
-                            .red()
-                    );
-                    print!(
-                        "{} ",
-                        "Are you absolutely sure you want to proceed? (y/N):"
-                            .bold()
-                            .yellow()
-                    );
-                    io::stdout()
-                        .flush()
-                        .context("Failed to flush stdout")
-                        .unwrap_or_else(|e| eprintln!("Error flushing stdout: {e}"));
-
-                    let mut confirmation = String::new();
-                    match io::stdin().read_line(&mut confirmation) {
-                        Ok(_) => {
-                            if confirmation.trim().eq_ignore_ascii_case("y") {
-                                println!("Proceeding to kill processes...");
-                                match kill_processes(&results) {
-                                    Ok(killed_count) => {
-                                        if killed_count > 0 {
-                                            println!(
-                                                "Successfully attempted to kill {killed_count} process(es)."
-                                            );
-                                        } else {
-                                            println!("No processes were targeted or killed.");
-                                        }
-                                        if killed_count < results.len() {
-                                            println!(
-                                                "{}",
-                                                "Note: Some processes might not have been killed due to errors, lack of permissions, or if they already exited."
-                                                .yellow()
-                                            );
-                                        }
-                                    }
-                                    Err(e) => eprintln!(
-                                        "An error occurred during the kill process: {e:?}"
-                                    ),
-                                }
-                            } else {
-                                println!("Operation cancelled by user.");
-                            }
-                        }
-                        Err(e) => {
-                            eprintln!("Failed to read user input: {e:?}. Operation cancelled.");
-                        }
-                    }
-                }
-            }
+    for err in &scan.errors {
+        eprintln!("Warning: {err:#}");
+    }
+
+    let results = &scan.lockers;
+    let ordered = order_lockers(results, cli.sort);
+    if cli.pids_only {
+        for locker in &ordered {
+            println!("{}", locker.pid);
         }
-        Err(err) => {
-            eprintln!("find_locker failed, err: {err:?}");
+    } else if cli.json {
+        print_json(ordered, &scan.errors);
+    } else if !cli.quiet {
+        print_lockers(&ordered, elapsed.as_secs_f64());
+    }
+
+    let kill_requested = cli.kill || !cli.kill_pid.is_empty() || !cli.kill_name.is_empty();
+    if kill_requested
+        && !results.is_empty()
+        && let Err(err) = kill::confirm_and_kill(&cli, &scan)
+    {
+        eprintln!("Error: {err:#}");
+        return ExitCode::from(EXIT_ERROR);
+    }
+
+    if cli.unlock
+        && !results.is_empty()
+        && let Err(err) = unlock::confirm_and_unlock(&cli, &scan)
+    {
+        eprintln!("Error: {err:#}");
+        return ExitCode::from(EXIT_ERROR);
+    }
+
+    if cli.first && !results.is_empty() {
+        // One locker is all --first looks for, whatever else failed.
+        ExitCode::from(EXIT_LOCKERS_FOUND)
+    } else if !scan.errors.is_empty() {
+        ExitCode::from(EXIT_PARTIAL_SCAN)
+    } else if results.is_empty() {
+        ExitCode::from(EXIT_NO_LOCKER)
+    } else {
+        ExitCode::from(EXIT_LOCKERS_FOUND)
+    }
+}
+
+/// Sorts the lockers, ties and lockers missing the sort key keep their pid order at the end.
+fn order_lockers(
+    lockers: &BTreeMap<u32, ProcessResult>,
+    order: LockerOrder,
+) -> Vec<&ProcessResult> {
+    let mut ordered: Vec<_> = lockers.values().collect();
+    match order {
+        LockerOrder::Pid => {}
+        LockerOrder::StartTime => {
+            ordered.sort_by_key(|locker| (locker.create_time == 0, locker.create_time))
         }
+        LockerOrder::Handles => ordered
+            .sort_by_key(|locker| Reverse(locker.counters.map(|counters| counters.handle_count))),
     }
+    ordered
 }
 
-fn kill_processes(processes: &HashMap<u32, ProcessResult>) -> anyhow::Result<usize> {
-    let mut killed_count = 0;
-    if processes.is_empty() {
-        println!("No processes to kill.");
-        return Ok(0);
+fn print_lockers(results: &[&ProcessResult], elapsed_secs: f64) {
+    if results.is_empty() {
+        println!("No locker found");
+        return;
     }
 
     println!(
-        "{}",
-        "IMPORTANT: Attempting to terminate processes. This can have unintended consequences."
-            .bold()
-            .red()
+        "Found {} locker(s) in {:.2}s:\n",
+        results.len(),
+        elapsed_secs
     );
-
-    for (pid, process_info) in processes {
-        println!(
-            "Attempting to kill process: PID {}, Name: '{}', Path: '{}'",
-            process_info.pid, process_info.name, process_info.path
-        );
-        match process_ext::kill_process_by_pid(*pid) {
-            Ok(_) => {
+    for result in results {
+        println!("pid: {}", result.pid);
+        println!("name: {}", result.name);
+        println!("path: {}", result.display_path());
+        match &result.owner {
+            Some(owner) => {
+                println!("user: {owner}");
+                println!("elevated: {}", if owner.elevated { "yes" } else { "no" });
+            }
+            None => {
+                println!("user: unknown");
+                println!("elevated: unknown");
+            }
+        }
+        match result.session_id {
+            Some(session_id) => println!("session: {session_id}"),
+            None => println!("session: unknown"),
+        }
+        match process_ext::filetime_to_system_time(result.create_time) {
+            Some(started) => println!("started: {}", audit::format_utc(started)),
+            None => println!("started: unknown"),
+        }
+        match result.parent_pid {
+            Some(parent_pid) => println!("parent pid: {parent_pid}"),
+            None => println!("parent pid: unknown"),
+        }
+        match result.counters {
+            Some(counters) => {
+                println!("handles: {}", counters.handle_count);
+                println!("threads: {}", counters.thread_count);
                 println!(
-                    "Successfully sent termination signal to process PID {pid}."
+                    "working set: {}",
+                    format_mebibytes(counters.working_set_size)
                 );
-                killed_count += 1;
             }
-            Err(e) => {
-                eprintln!("Failed to kill process PID {pid}: {e:?}");
-                // Ignore the error and continue
+            None => {
+                println!("handles: unknown");
+                println!("threads: unknown");
+                println!("working set: unknown");
             }
         }
+        println!();
     }
-    Ok(killed_count)
 }
 
-fn find_locker(cli: &Cli) -> anyhow::Result<HashMap<u32, ProcessResult>> {
-    let reference_path = &cli.path;
+fn format_mebibytes(bytes: usize) -> String {
+    format!("{:.1} MiB", bytes as f64 / (1024.0 * 1024.0))
+}
+
+/// The scan as printed by `--json`.
+#[derive(Serialize)]
+struct JsonReport<'a> {
+    lockers: Vec<&'a ProcessResult>,
+    /// Scan steps that failed, `lockers` may be incomplete if this is not empty.
+    errors: Vec<String>,
+}
 
-    if reference_path.is_empty() {
-        return Err(anyhow::anyhow!("Path cannot be empty"));
+fn print_json(lockers: Vec<&ProcessResult>, errors: &[anyhow::Error]) {
+    let report = JsonReport {
+        lockers,
+        errors: errors.iter().map(|err| format!("{err:#}")).collect(),
+    };
+    match serde_json::to_string_pretty(&report) {
+        Ok(json) => println!("{json}"),
+        Err(err) => eprintln!("Error: Failed to serialize the lockers: {err}"),
     }
+}
+
+/// Finds the processes locking any of the paths, all of them are matched in a single pass over
+/// the handles and the modules.
+fn find_locker(reference_paths: &[String], options: &ScanOptions) -> anyhow::Result<ScanResult> {
+    scan_paths(reference_paths, options, false)
+}
 
-    if !Path::new(reference_path).exists() {
-        return Err(anyhow::anyhow!("Path does not exist: {}", reference_path));
+/// Like [`find_locker`], but stops as soon as one locker is found.
+fn find_first_locker(
+    reference_paths: &[String],
+    options: &ScanOptions,
+) -> anyhow::Result<ScanResult> {
+    scan_paths(reference_paths, options, true)
+}
+
+fn scan_paths(
+    reference_paths: &[String],
+    options: &ScanOptions,
+    stop_at_first: bool,
+) -> anyhow::Result<ScanResult> {
+    let mut nt_paths = Vec::with_capacity(reference_paths.len());
+    for reference_path in reference_paths {
+        if reference_path.is_empty() {
+            return Err(anyhow::anyhow!("Path cannot be empty"));
+        }
+
+        if !Path::new(reference_path).exists() {
+            return Err(anyhow::anyhow!("Path does not exist: {}", reference_path));
+        }
+
+        let nt_path = path_ext::win32_path_to_nt_path(reference_path).with_context(|| {
+            format!("Failed to convert Win32 path to NT path: {reference_path}")
+        })?;
+        nt_paths.push(nt_path);
     }
+    let targets = path_ext::PathTrie::new(&nt_paths);
+
+    let mut scan = ScanResult::default();
+    // Decided before the handle table is captured, so that the files opened to check them are
+    // closed by then.
+    let scan_modules = options.scan_modules(reference_paths);
 
-    let nt_path = path_ext::win32_path_to_nt_path(reference_path)
-        .with_context(|| "Failed to convert Win32 path to NT path")?;
+    // The process snapshot is taken before the handle table, so if a pid gets reused in between,
+    // the locker keeps the identity of the old process and any action on it is refused.
+    let process_table = process_ext::ProcessTable::capture(options.max_buffer())
+        .with_context(|| "Failed to enumerate processes");
+    let empty_table = ProcessTable::default();
+    let lookup_table = process_table.as_ref().unwrap_or(&empty_table);
 
-    let mut process_results = HashMap::<u32, ProcessResult>::new();
+    // Filtered out processes are dropped before their handles or modules are looked at.
+    let process_filter = options.filter()?;
+    let selected = if process_filter.is_empty() {
+        None
+    } else {
+        let process_table = process_table
+            .as_ref()
+            .map_err(|err| anyhow::anyhow!("{err:#}, which the process filters need"))?;
+        Some(process_filter.select(process_table))
+    };
+    // Processes that started after the snapshot are not in `selected`, they are checked live,
+    // once per pid since this runs for every handle.
+    let late_selected = RefCell::new(HashMap::new());
+    let is_selected = |pid: u32| {
+        selected.as_ref().is_none_or(|selected| {
+            selected.contains(&pid)
+                || (lookup_table.get(pid).is_none()
+                    && *late_selected
+                        .borrow_mut()
+                        .entry(pid)
+                        .or_insert_with(|| process_filter.matches_live(pid)))
+        })
+    };
+
+    // A failure in one of the two scans below only makes the result partial,
+    // the other one can still find lockers.
+    let handle_stream =
+        handle_ext::stream_handles(options.jobs(), options.max_buffer(), is_selected)
+            .with_context(|| "Failed to enumerate handles");
+    let handles_failed = handle_stream.is_err();
+    match handle_stream {
+        Ok(handle_stream) => {
+            let mut unresolved = Vec::new();
+            // Each batch holds every handle of one process, so the lockers come out the same
+            // whatever order the processes are resolved in.
+            for batch in handle_stream {
+                let pid = batch.pid;
+                unresolved.extend(
+                    batch
+                        .unresolved
+                        .iter()
+                        .map(|&handle_value| (pid, handle_value)),
+                );
+                for handle_info in batch.handles {
+                    if targets.matches(&handle_info.nt_path) {
+                        let process_result = scan.lockers.entry(pid).or_insert_with(|| {
+                            ProcessResult::describe(lookup_table, &lookup_table.identity(pid))
+                        });
+                        process_result.handles.push(handle_info);
+                    }
+                }
+                // Dropping the stream stops the resolution of the remaining processes.
+                if stop_at_first && !scan.lockers.is_empty() {
+                    break;
+                }
+            }
+
+            if !unresolved.is_empty() {
+                unresolved.sort_unstable();
+                let unresolved: Vec<_> = unresolved
+                    .iter()
+                    .map(|(pid, handle_value)| format!("PID {pid} handle 0x{handle_value:x}"))
+                    .collect();
+                scan.errors.push(anyhow::anyhow!(
+                    "{} handle(s) are unresolved, their name query did not return in time and any of them may lock the path: {}",
+                    unresolved.len(),
+                    unresolved.join(", ")
+                ));
+            }
+        }
+        Err(err) => scan.errors.push(err),
+    }
 
-    let handle_infos = handle_ext::enum_handles().with_context(|| "Failed to enumerate handles")?;
+    if let Ok(process_table) = &process_table
+        && scan_modules
+        && (!stop_at_first || scan.lockers.is_empty())
+    {
+        find_module_lockers(
+            &targets,
+            process_table,
+            is_selected,
+            &mut scan.lockers,
+            stop_at_first,
+        );
+    }
 
-    for handle_info in handle_infos {
-        if path_ext::is_same_or_ancestor_of(&nt_path, &handle_info.nt_path) {
-            let pid = handle_info.pid;
-            let name =
-                process_ext::pid_to_process_name(pid).unwrap_or_else(|_| "unknown".to_string());
-            let path = process_ext::pid_to_process_full_path(pid)
-                .unwrap_or_else(|_| "unknown".to_string());
-            let process_result = ProcessResult { pid, name, path };
-            process_results.insert(pid, process_result);
+    match process_table {
+        Ok(process_table) => scan.processes = process_table,
+        Err(err) => {
+            if handles_failed {
+                return Err(anyhow::anyhow!("{:#}; {err:#}", scan.errors[0]));
+            }
+            scan.errors.push(err);
         }
     }
 
-    let proces_infos =
-        process_ext::enum_processes().with_context(|| "Failed to enumerate processes")?;
-    for process_info in proces_infos {
-        for module in &process_info.modules {
-            if path_ext::is_same_or_ancestor_of(&nt_path, module) {
-                let process_result = ProcessResult {
-                    pid: process_info.pid,
-                    name: process_info.process_name.clone(),
-                    path: process_info.process_full_path.clone(),
-                };
-                process_results.insert(process_info.pid, process_result);
+    Ok(scan)
+}
+
+/// Adds the processes that have the path, or a file under it, loaded as a module.
+fn find_module_lockers(
+    targets: &path_ext::PathTrie,
+    process_table: &ProcessTable,
+    include_pid: impl Fn(u32) -> bool,
+    lockers: &mut BTreeMap<u32, ProcessResult>,
+    stop_at_first: bool,
+) {
+    for process_info in process_table
+        .as_slice()
+        .iter()
+        .filter(|process_info| include_pid(process_info.pid))
+    {
+        let identity = process_info.identity();
+        // Processes that cannot be opened are skipped, like in the handle scan.
+        let Ok(modules) = process_ext::enum_process_modules(&identity) else {
+            continue;
+        };
+        for module in modules {
+            if targets.matches(&module) {
+                let process_result = lockers
+                    .entry(process_info.pid)
+                    .or_insert_with(|| ProcessResult::describe(process_table, &identity));
+                process_result.modules.push(module);
             }
         }
+        if stop_at_first && !lockers.is_empty() {
+            return;
+        }
     }
+}
 
-    Ok(process_results)
+#[derive(Debug, Default)]
+struct ScanResult {
+    lockers: BTreeMap<u32, ProcessResult>,
+    /// The process snapshot the lockers were matched against.
+    processes: ProcessTable,
+    /// Scan steps that failed, `lockers` may be incomplete if this is not empty.
+    errors: Vec<anyhow::Error>,
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone, Default, Serialize)]
 struct ProcessResult {
     pid: u32,
+    /// Creation time of the process, see [`ProcessIdentity`].
+    create_time: u64,
     name: String,
-    path: String,
+    /// Image path of the process, `None` if it could not be queried.
+    path: Option<String>,
+    /// This and the other snapshot fields are `None` if the process started after the snapshot.
+    parent_pid: Option<u32>,
+    session_id: Option<u32>,
+    counters: Option<process_ext::ProcessCounters>,
+    owner: Option<process_ext::ProcessOwner>,
+    /// Open handles to the path.
+    handles: Vec<handle_ext::HandleInfo>,
+    /// NT paths of the matching modules loaded by the process.
+    modules: Vec<String>,
+}
+
+impl ProcessResult {
+    /// Describes a process that has no handle or module recorded yet.
+    fn describe(process_table: &ProcessTable, identity: &ProcessIdentity) -> Self {
+        let description = process_table.describe(identity);
+        Self {
+            pid: identity.pid,
+            create_time: identity.create_time,
+            name: description.name,
+            path: description.path,
+            parent_pid: description.parent_pid,
+            session_id: description.session_id,
+            counters: description.counters,
+            owner: description.owner,
+            ..Default::default()
+        }
+    }
+
+    fn identity(&self) -> ProcessIdentity {
+        ProcessIdentity {
+            pid: self.pid,
+            create_time: self.create_time,
+        }
+    }
+
+    /// The image path, or "unknown" if it could not be queried.
+    fn display_path(&self) -> &str {
+        self.path.as_deref().unwrap_or("unknown")
+    }
 }
@@ -4,74 +4,208 @@ use windows::{
         Foundation::{NtQueryObject, OBJECT_INFORMATION_CLASS},
         System::SystemInformation::{NtQuerySystemInformation, SYSTEM_INFORMATION_CLASS},
     },
-    Win32::Foundation::{MAX_PATH, STATUS_BUFFER_OVERFLOW, STATUS_INFO_LENGTH_MISMATCH},
+    Win32::Foundation::{MAX_PATH, NTSTATUS, STATUS_BUFFER_OVERFLOW, STATUS_INFO_LENGTH_MISMATCH},
 };
 
 use crate::safe_handle::SafeHandle;
 
+/// Default cap on the buffer of a system information query, in bytes.
+pub const DEFAULT_MAX_BUFFER: usize = 1024 * 1024 * 1024;
+
+/// Cap on the buffer of an object query. Object names are `UNICODE_STRING`s, so they never
+/// come close to it.
+const MAX_OBJECT_BUFFER: usize = 1024 * 1024;
+
+/// Queries that still do not fit after this many attempts are given up on.
+const MAX_ATTEMPTS: usize = 16;
+
+/// Queries system information, growing the buffer until it fits or reaches `max_len` bytes.
 pub fn nt_query_information_loop(
     sys_info_class: SYSTEM_INFORMATION_CLASS,
+    max_len: usize,
 ) -> anyhow::Result<Vec<u8>> {
-    let mut buffer = vec![0u8; 1024 * 1024];
-
-    loop {
-        let mut return_len = 0u32;
-        let nt_status = unsafe {
+    query_growing(
+        "NtQuerySystemInformation",
+        1024 * 1024,
+        max_len,
+        |nt_status| nt_status == STATUS_INFO_LENGTH_MISMATCH,
+        |buffer, return_len| unsafe {
             NtQuerySystemInformation(
                 sys_info_class,
                 buffer.as_mut_ptr() as *mut _,
                 buffer.len() as u32,
-                &mut return_len,
+                return_len,
+            )
+        },
+    )
+}
+
+pub fn nt_query_object_loop(
+    safe_handle: &SafeHandle,
+    obj_info_class: OBJECT_INFORMATION_CLASS,
+) -> anyhow::Result<Vec<u8>> {
+    query_growing(
+        "NtQueryObject",
+        MAX_PATH as usize,
+        MAX_OBJECT_BUFFER,
+        |nt_status| nt_status == STATUS_INFO_LENGTH_MISMATCH || nt_status == STATUS_BUFFER_OVERFLOW,
+        |buffer, return_len| unsafe {
+            NtQueryObject(
+                Some(safe_handle.handle),
+                obj_info_class,
+                Some(buffer.as_mut_ptr() as *mut _),
+                buffer.len() as u32,
+                Some(return_len),
             )
-        };
+        },
+    )
+}
 
-        if nt_status == STATUS_INFO_LENGTH_MISMATCH {
-            buffer.resize(return_len as usize, 0);
+/// Runs a query that reports a too small buffer through `is_too_small`, growing the buffer
+/// until it succeeds.
+///
+/// The length the query asks for is only a hint: the data can grow before the next attempt,
+/// and some queries report less than they need, or nothing at all.
+fn query_growing(
+    function_name: &str,
+    initial_len: usize,
+    max_len: usize,
+    is_too_small: impl Fn(NTSTATUS) -> bool,
+    mut query: impl FnMut(&mut [u8], &mut u32) -> NTSTATUS,
+) -> anyhow::Result<Vec<u8>> {
+    let mut buffer = vec![0u8; initial_len.min(max_len)];
+
+    for _ in 0..MAX_ATTEMPTS {
+        let mut return_len = 0u32;
+        let nt_status = query(&mut buffer, &mut return_len);
+
+        if is_too_small(nt_status) {
+            if buffer.len() >= max_len {
+                return Err(anyhow!(
+                    "{function_name} needs a buffer larger than the limit of {max_len} bytes"
+                ));
+            }
+            buffer = vec![0u8; grown_len(buffer.len(), return_len).min(max_len)];
             continue;
         }
 
         if nt_status.is_err() {
             return Err(anyhow!(
-                "NtQuerySystemInformation failed, nt_status: {:?}",
+                "{function_name} failed, nt_status: {:?}",
                 nt_status
             ));
         }
 
-        break;
+        return Ok(buffer);
     }
 
-    Ok(buffer)
+    Err(anyhow!(
+        "{function_name} still reported a too small buffer after {MAX_ATTEMPTS} attempts"
+    ))
 }
 
-pub fn nt_query_object_loop(
-    safe_handle: &SafeHandle,
-    obj_info_class: OBJECT_INFORMATION_CLASS,
-) -> anyhow::Result<Vec<u8>> {
-    let mut buffer = vec![0u8; MAX_PATH as usize];
+/// Length of the next attempt: what the query asked for, at least one byte more than the
+/// current buffer, plus half of it as headroom for data added in the meantime.
+fn grown_len(current_len: usize, return_len: u32) -> usize {
+    let needed = (return_len as usize).max(current_len + 1);
+    needed.saturating_add(needed / 2)
+}
 
-    loop {
-        let mut return_len = 0u32;
-        let nt_status = unsafe {
-            NtQueryObject(
-                Some(safe_handle.handle),
-                obj_info_class,
-                Some(buffer.as_mut_ptr() as *mut _),
-                buffer.len() as u32,
-                Some(&mut return_len),
-            )
-        };
+#[cfg(test)]
+mod tests {
+    use super::*;
 
-        if nt_status == STATUS_INFO_LENGTH_MISMATCH || nt_status == STATUS_BUFFER_OVERFLOW {
-            buffer.resize(return_len as usize, 0);
-            continue;
+    use windows::Win32::Foundation::{STATUS_ACCESS_DENIED, STATUS_SUCCESS};
+
+    /// A query over data that grows by `growth` bytes on every call, like a busy handle table.
+    fn growing_data(
+        mut size: usize,
+        growth: usize,
+        report: impl Fn(usize) -> u32,
+    ) -> impl FnMut(&mut [u8], &mut u32) -> NTSTATUS {
+        move |buffer, return_len| {
+            let fits = buffer.len() >= size;
+            *return_len = report(size);
+            size += growth;
+            if fits {
+                STATUS_SUCCESS
+            } else {
+                STATUS_INFO_LENGTH_MISMATCH
+            }
         }
+    }
 
-        if nt_status.is_err() {
-            return Err(anyhow!("NtQueryObject failed, nt_status: {:?}", nt_status));
+    fn is_too_small(nt_status: NTSTATUS) -> bool {
+        nt_status == STATUS_INFO_LENGTH_MISMATCH
+    }
+
+    #[test]
+    fn test_query_growing_fits_first_time() {
+        let mut calls = 0;
+        let buffer = query_growing("Query", 64, 1024, is_too_small, |_, _| {
+            calls += 1;
+            STATUS_SUCCESS
+        })
+        .unwrap();
+        assert_eq!(buffer.len(), 64);
+        assert_eq!(calls, 1);
+    }
+
+    #[test]
+    fn test_query_growing_outpaces_growth() {
+        // Resizing to exactly the reported length would never catch up with this data.
+        let query = growing_data(1000, 100, |size| size as u32);
+        let buffer = query_growing("Query", 16, 1024 * 1024, is_too_small, query).unwrap();
+        assert!(buffer.len() >= 1100);
+    }
+
+    #[test]
+    fn test_query_growing_survives_lying_lengths() {
+        let reports: [fn(usize) -> u32; 2] = [|_| 0, |size| (size / 2) as u32];
+        for report in reports {
+            let query = growing_data(5000, 10, report);
+            let buffer = query_growing("Query", 16, 1024 * 1024, is_too_small, query).unwrap();
+            assert!(buffer.len() >= 5000);
         }
+    }
 
-        break;
+    #[test]
+    fn test_query_growing_stops_at_cap() {
+        let mut largest = 0;
+        let err = query_growing("Query", 16, 4096, is_too_small, |buffer, return_len| {
+            largest = largest.max(buffer.len());
+            *return_len = 1024 * 1024;
+            STATUS_INFO_LENGTH_MISMATCH
+        })
+        .unwrap_err();
+        assert!(err.to_string().contains("limit of 4096 bytes"), "{err}");
+        assert_eq!(largest, 4096);
     }
 
-    Ok(buffer)
+    #[test]
+    fn test_query_growing_gives_up_after_max_attempts() {
+        // Never fits, as if the data kept pace with the buffer.
+        let mut calls = 0;
+        let err = query_growing(
+            "Query",
+            16,
+            usize::MAX,
+            is_too_small,
+            |buffer, return_len| {
+                calls += 1;
+                *return_len = buffer.len() as u32;
+                STATUS_INFO_LENGTH_MISMATCH
+            },
+        )
+        .unwrap_err();
+        assert!(err.to_string().contains("attempts"), "{err}");
+        assert_eq!(calls, MAX_ATTEMPTS);
+    }
+
+    #[test]
+    fn test_query_growing_reports_other_errors() {
+        let err = query_growing("Query", 16, 1024, is_too_small, |_, _| STATUS_ACCESS_DENIED)
+            .unwrap_err();
+        assert!(err.to_string().starts_with("Query failed"), "{err}");
+    }
 }
@@ -1,3 +1,7 @@
+use std::fs::File;
+use std::io::{self, Read};
+use std::path::Path;
+
 use anyhow::anyhow;
 use windows::{
     Win32::Storage::FileSystem::{
@@ -37,6 +41,21 @@ pub fn win32_path_to_nt_path(win32_path: impl AsRef<str>) -> anyhow::Result<Stri
     Ok(nt_path)
 }
 
+/// Checks if the path could be loaded as a module: a directory, which may contain one, or a file
+/// starting with the `MZ` signature of executable images. A file that cannot be read is assumed
+/// to be one.
+pub fn may_be_image(path: &Path) -> bool {
+    if path.is_dir() {
+        return true;
+    }
+
+    let mut signature = [0u8; 2];
+    match File::open(path).and_then(|mut file| file.read_exact(&mut signature)) {
+        Ok(()) => signature == *b"MZ",
+        Err(err) => err.kind() != io::ErrorKind::UnexpectedEof,
+    }
+}
+
 /// Checks if the `reference_path` is the same as or an ancestor of the `subject_path`.
 ///
 /// This function is useful for determining if a file or directory (`subject_path`)
@@ -54,6 +73,9 @@ pub fn win32_path_to_nt_path(win32_path: impl AsRef<str>) -> anyhow::Result<Stri
 ///
 /// `true` if `reference_path` is the same as or an ancestor of `subject_path`,
 /// `false` otherwise. On Windows, the comparison is case-insensitive.
+///
+/// Kept as the reference [`PathTrie`] is checked against.
+#[cfg(test)]
 pub fn is_same_or_ancestor_of(reference_path: &str, subject_path: &str) -> bool {
     let ref_len = reference_path.len();
     let sub_len = subject_path.len();
@@ -89,9 +111,110 @@ pub fn is_same_or_ancestor_of(reference_path: &str, subject_path: &str) -> bool
     false
 }
 
+/// Index of target paths, to match a path against all of them in one walk over its bytes.
+///
+/// A path matches if one of the targets is the same as or an ancestor of it, compared
+/// case-insensitively. A target ending with a separator matches anything under it, otherwise
+/// the path has to continue with a separator, so `C:\Us` does not match `C:\Users`.
+#[derive(Debug)]
+pub struct PathTrie {
+    /// The root is the empty prefix.
+    nodes: Vec<TrieNode>,
+}
+
+#[derive(Debug, Default)]
+struct TrieNode {
+    /// Case-folded byte and index of the child node.
+    children: Vec<(u8, usize)>,
+    /// Whether a target ends at this node.
+    is_target: bool,
+}
+
+impl PathTrie {
+    pub fn new<S: AsRef<str>>(targets: impl IntoIterator<Item = S>) -> Self {
+        let mut trie = Self {
+            nodes: vec![TrieNode::default()],
+        };
+        for target in targets {
+            trie.insert(target.as_ref());
+        }
+        trie
+    }
+
+    fn insert(&mut self, target: &str) {
+        let mut node = 0;
+        for byte in target.bytes().map(|byte| byte.to_ascii_lowercase()) {
+            node = match self.child(node, byte) {
+                Some(child) => child,
+                None => {
+                    self.nodes.push(TrieNode::default());
+                    let child = self.nodes.len() - 1;
+                    self.nodes[node].children.push((byte, child));
+                    child
+                }
+            };
+        }
+        self.nodes[node].is_target = true;
+    }
+
+    fn child(&self, node: usize, byte: u8) -> Option<usize> {
+        self.nodes[node]
+            .children
+            .iter()
+            .find(|(child_byte, _)| *child_byte == byte)
+            .map(|(_, child)| *child)
+    }
+
+    /// Checks if one of the targets is the same as or an ancestor of the path.
+    pub fn matches(&self, path: &str) -> bool {
+        let bytes = path.as_bytes();
+        let mut node = 0;
+        for index in 0..=bytes.len() {
+            // A target ending here matches the whole path, or a path under it if it ends with a
+            // separator or the path continues with one.
+            if self.nodes[node].is_target
+                && (index == bytes.len()
+                    || bytes[index] == b'\\'
+                    || (index > 0 && bytes[index - 1] == b'\\'))
+            {
+                return true;
+            }
+            let Some(&byte) = bytes.get(index) else {
+                break;
+            };
+            match self.child(node, byte.to_ascii_lowercase()) {
+                Some(child) => node = child,
+                None => break,
+            }
+        }
+        false
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
+    use std::time::Instant;
+
+    #[test]
+    fn test_may_be_image() {
+        let dir =
+            std::env::temp_dir().join(format!("locksmith-may-be-image-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let write = |name: &str, content: &[u8]| {
+            let path = dir.join(name);
+            std::fs::write(&path, content).unwrap();
+            path
+        };
+
+        assert!(may_be_image(&dir));
+        assert!(may_be_image(&write("plugin.bin", b"MZ\x90\x00")));
+        assert!(!may_be_image(&write("notes.txt", b"hello")));
+        assert!(!may_be_image(&write("empty.txt", b"")));
+        assert!(!may_be_image(&write("short.txt", b"M")));
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
 
     #[test]
     fn test_is_same_or_ancestor_of_exact_match() {
@@ -168,4 +291,98 @@ mod tests {
         // Example: ref = "A\B", subject = "A\BC" (should be false)
         assert!(!is_same_or_ancestor_of(r"C:\Us", r"C:\Users"));
     }
+
+    #[test]
+    fn test_path_trie_agrees_with_is_same_or_ancestor_of() {
+        let paths = [
+            "",
+            r"\",
+            r"C:\Users",
+            r"C:\Users\",
+            r"C:\USERS\TestUser",
+            r"C:\Users\TestUser\Documents\file.txt",
+            r"C:\User",
+            r"C:\Us",
+            r"C:\Users\Project",
+            r"C:\Windows",
+            r"\Device\HarddiskVolume3\Build\out.dll",
+            r"\device\harddiskvolume3\build",
+        ];
+        for target in paths {
+            let trie = PathTrie::new([target]);
+            for path in paths {
+                assert_eq!(
+                    trie.matches(path),
+                    is_same_or_ancestor_of(target, path),
+                    "target: {target:?}, path: {path:?}"
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn test_path_trie_matches_any_target() {
+        let trie = PathTrie::new([r"C:\Build\out", r"C:\Build\obj\", r"D:\data.db"]);
+        assert!(trie.matches(r"C:\BUILD\OUT\app.exe"));
+        assert!(trie.matches(r"C:\Build\obj\x.o"));
+        assert!(trie.matches(r"d:\DATA.db"));
+        assert!(!trie.matches(r"C:\Build\output"));
+        assert!(!trie.matches(r"C:\Build"));
+        assert!(!trie.matches(r"D:\data.db-journal"));
+        assert!(!PathTrie::new(Vec::<&str>::new()).matches(r"C:\Build"));
+    }
+
+    /// Handle-like paths: `files` files in each of `dirs` directories of one volume.
+    fn synthetic_paths(dirs: usize, files: usize) -> Vec<String> {
+        (0..dirs)
+            .flat_map(|dir| {
+                (0..files).map(move |file| {
+                    format!(r"\Device\HarddiskVolume3\Projects\repo{dir}\src\module{file}.rs")
+                })
+            })
+            .collect()
+    }
+
+    // cargo test bench_path_trie --release -- --ignored --nocapture
+    #[test]
+    #[ignore]
+    fn bench_path_trie() {
+        let handle_paths = synthetic_paths(500, 200);
+        let targets: Vec<String> = synthetic_paths(100, 50)
+            .into_iter()
+            .map(|path| path.to_uppercase())
+            .collect();
+
+        let start = Instant::now();
+        let pairwise = handle_paths
+            .iter()
+            .filter(|path| {
+                targets
+                    .iter()
+                    .any(|target| is_same_or_ancestor_of(target, path))
+            })
+            .count();
+        let pairwise_elapsed = start.elapsed();
+        println!(
+            "{} handles x {} targets: pairwise {pairwise_elapsed:?}",
+            handle_paths.len(),
+            targets.len()
+        );
+
+        let start = Instant::now();
+        let trie = PathTrie::new(&targets);
+        let indexed = handle_paths
+            .iter()
+            .filter(|path| trie.matches(path))
+            .count();
+        let indexed_elapsed = start.elapsed();
+
+        assert_eq!(pairwise, indexed);
+        println!(
+            "{} handles x {} targets: trie {indexed_elapsed:?} ({:.0}x)",
+            handle_paths.len(),
+            targets.len(),
+            pairwise_elapsed.as_secs_f64() / indexed_elapsed.as_secs_f64()
+        );
+    }
 }
@@ -0,0 +1,575 @@
+use std::collections::{BTreeMap, HashSet};
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::process::ExitCode;
+use std::time::{Duration, SystemTime};
+
+use anyhow::Context;
+use clap::{Args, ValueEnum};
+use colored::Colorize;
+use serde::{Deserialize, Serialize};
+
+use crate::audit::{self, AuditLog};
+use crate::handle_ext::HandleInfo;
+use crate::kill::{confirm, exclude_protected, kill_processes, print_skipped};
+use crate::path_ext;
+use crate::protect::{Denylist, ProtectRule};
+use crate::unlock::{closable_handles, close_handles, print_handles};
+use crate::{EXIT_ERROR, EXIT_PARTIAL_SCAN, ProcessResult, ScanOptions, find_locker};
+
+/// Version of the plan file format, bumped on incompatible changes.
+const PLAN_VERSION: u32 = 1;
+
+#[derive(Args, Debug)]
+pub struct PlanArgs {
+    /// Path to the file whose lockers should be planned for
+    pub path: String,
+
+    /// File to write the plan to
+    #[arg(short, long, value_name = "FILE")]
+    pub output: PathBuf,
+
+    /// What the plan should do to the lockers
+    #[arg(long, value_enum, default_value_t = PlanAction::Kill)]
+    pub action: PlanAction,
+
+    /// Never plan for this process, given as a pid, an image name or an image path
+    /// (can be repeated)
+    #[arg(long, value_name = "PID|NAME|PATH")]
+    pub protect: Vec<ProtectRule>,
+
+    /// Also plan for protected processes such as csrss.exe, the System process or
+    /// locksmith's parent
+    #[arg(long, default_value_t = false)]
+    pub allow_protected: bool,
+}
+
+#[derive(Args, Debug)]
+pub struct ApplyArgs {
+    /// Plan written by `locksmith plan`
+    pub plan: PathBuf,
+
+    /// Seconds to wait for a locker to exit after asking its windows to close before
+    /// terminating it forcefully, 0 terminates it forcefully right away
+    #[arg(long, value_name = "SECONDS", default_value_t = 5)]
+    pub grace_period: u64,
+
+    /// Seconds to wait for a locker to exit once it has been terminated
+    #[arg(long, value_name = "SECONDS", default_value_t = 10)]
+    pub exit_timeout: u64,
+
+    /// Skip the confirmation prompt, required when stdin is not a terminal
+    #[arg(short = 'y', long, default_value_t = false)]
+    pub yes: bool,
+
+    /// Re-validate the plan and print what would be done without touching any process
+    #[arg(long, default_value_t = false)]
+    pub dry_run: bool,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum PlanAction {
+    /// Kill the lockers
+    Kill,
+    /// Close the handles to the file inside the lockers
+    Unlock,
+}
+
+/// The actions `locksmith apply` is allowed to take, as written by `locksmith plan`.
+#[derive(Debug, PartialEq, Serialize, Deserialize)]
+pub struct Plan {
+    pub version: u32,
+    /// UTC time the plan was made, RFC 3339.
+    pub created: String,
+    /// Absolute Win32 path of the target, as resolved when the plan was made.
+    pub target_path: String,
+    /// NT path the target resolved to, `apply` refuses to run if it now resolves elsewhere.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub target_nt_path: Option<String>,
+    /// Whether protected processes were allowed into the plan, re-checked on apply.
+    #[serde(default)]
+    pub allow_protected: bool,
+    pub entries: Vec<PlanEntry>,
+}
+
+/// A single planned kill of a process, or close of one of its handles.
+#[derive(Debug, PartialEq, Serialize, Deserialize)]
+pub struct PlanEntry {
+    pub action: PlanAction,
+    pub pid: u32,
+    /// Creation time of the process, in 100ns intervals since January 1, 1601 (UTC).
+    pub create_time: u64,
+    pub name: String,
+    /// `null` if the image path could not be queried.
+    pub image_path: Option<String>,
+    /// Handle value to close, for `unlock` entries.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub handle: Option<usize>,
+    /// NT path the handle pointed to when the plan was made, for `unlock` entries.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub nt_path: Option<String>,
+}
+
+impl PlanEntry {
+    fn new(action: PlanAction, locker: &ProcessResult, handle: Option<&HandleInfo>) -> Self {
+        Self {
+            action,
+            pid: locker.pid,
+            create_time: locker.create_time,
+            name: locker.name.clone(),
+            image_path: locker.path.clone(),
+            handle: handle.map(|handle| handle.handle_value),
+            nt_path: handle.map(|handle| handle.nt_path.clone()),
+        }
+    }
+
+    fn describe(&self) -> String {
+        match self.handle {
+            Some(handle) => format!("handle 0x{handle:x} of PID {} ('{}')", self.pid, self.name),
+            None => format!("PID {} ('{}')", self.pid, self.name),
+        }
+    }
+}
+
+/// The part of a plan that still holds against a fresh scan.
+#[derive(Debug, Default)]
+pub struct Validation<'a> {
+    pub kills: Vec<&'a ProcessResult>,
+    pub unlocks: Vec<(&'a ProcessResult, &'a HandleInfo)>,
+    /// Differences between the plan and the live system, one message each.
+    pub drift: Vec<String>,
+}
+
+/// Resolves the path given to `plan` to an absolute Win32 path and to its NT path, so that
+/// `apply` acts on the same file whatever its working directory and drive mappings.
+fn resolve_target(path: &str) -> anyhow::Result<(String, String)> {
+    let absolute = std::path::absolute(path)
+        .with_context(|| format!("Failed to make '{path}' absolute"))?
+        .to_string_lossy()
+        .into_owned();
+    let nt_path = path_ext::win32_path_to_nt_path(&absolute)
+        .with_context(|| format!("Failed to resolve '{absolute}'"))?;
+    Ok((absolute, nt_path))
+}
+
+/// Writes the plan for the lockers of a path.
+///
+/// Exits with 0 once the plan is written, 2 if it was written from a partial scan, or 3 on error.
+pub fn run_plan(args: &PlanArgs, scan_options: &ScanOptions) -> ExitCode {
+    let (target_path, target_nt_path) = match resolve_target(&args.path) {
+        Ok(resolved) => resolved,
+        Err(err) => {
+            eprintln!("Error: {err:#}");
+            return ExitCode::from(EXIT_ERROR);
+        }
+    };
+    let scan = match find_locker(std::slice::from_ref(&target_path), scan_options) {
+        Ok(scan) => scan,
+        Err(err) => {
+            eprintln!("Error: {err:#}");
+            return ExitCode::from(EXIT_ERROR);
+        }
+    };
+    for err in &scan.errors {
+        eprintln!("Warning: {err:#}");
+    }
+
+    let targets: Vec<_> = scan.lockers.values().collect();
+    let (targets, skipped) = if args.allow_protected {
+        (targets, Vec::new())
+    } else {
+        exclude_protected(
+            targets,
+            &Denylist::new(&args.protect).with_ancestors(scan.processes.as_slice()),
+        )
+    };
+    print_skipped(&skipped);
+
+    let entries: Vec<_> = match args.action {
+        PlanAction::Kill => targets
+            .into_iter()
+            .map(|target| PlanEntry::new(PlanAction::Kill, target, None))
+            .collect(),
+        PlanAction::Unlock => closable_handles(targets)
+            .into_iter()
+            .map(|(target, handle)| PlanEntry::new(PlanAction::Unlock, target, Some(handle)))
+            .collect(),
+    };
+    let plan = Plan {
+        version: PLAN_VERSION,
+        created: audit::format_utc(SystemTime::now()),
+        target_path,
+        target_nt_path: Some(target_nt_path),
+        allow_protected: args.allow_protected,
+        entries,
+    };
+
+    if let Err(err) = write_plan(&plan, &args.output) {
+        eprintln!("Error: {err:#}");
+        return ExitCode::from(EXIT_ERROR);
+    }
+
+    println!(
+        "Wrote a plan with {} action(s) to '{}':",
+        plan.entries.len(),
+        args.output.display()
+    );
+    for entry in &plan.entries {
+        println!("  {:?} {}", entry.action, entry.describe());
+    }
+
+    if scan.errors.is_empty() {
+        ExitCode::SUCCESS
+    } else {
+        ExitCode::from(EXIT_PARTIAL_SCAN)
+    }
+}
+
+/// Re-validates a plan against the live system and executes the entries that still hold.
+///
+/// Exits with 0 if every entry was applied, 2 if some drifted or failed, or 3 on error.
+pub fn run_apply(
+    args: &ApplyArgs,
+    scan_options: &ScanOptions,
+    audit_log_path: Option<&Path>,
+) -> ExitCode {
+    match apply(args, scan_options, audit_log_path) {
+        Ok(true) => ExitCode::SUCCESS,
+        Ok(false) => ExitCode::from(EXIT_PARTIAL_SCAN),
+        Err(err) => {
+            eprintln!("Error: {err:#}");
+            ExitCode::from(EXIT_ERROR)
+        }
+    }
+}
+
+/// Returns whether the whole plan was applied as written.
+fn apply(
+    args: &ApplyArgs,
+    scan_options: &ScanOptions,
+    audit_log_path: Option<&Path>,
+) -> anyhow::Result<bool> {
+    let plan = read_plan(&args.plan)?;
+    if let Some(planned) = &plan.target_nt_path {
+        let current = path_ext::win32_path_to_nt_path(&plan.target_path)
+            .with_context(|| format!("Failed to resolve '{}'", plan.target_path))?;
+        if !current.eq_ignore_ascii_case(planned) {
+            return Err(anyhow::anyhow!(
+                "'{}' now resolves to '{current}', but the plan was made for '{planned}'",
+                plan.target_path
+            ));
+        }
+    }
+    let scan = find_locker(std::slice::from_ref(&plan.target_path), scan_options)?;
+    for err in &scan.errors {
+        eprintln!("Warning: {err:#}");
+    }
+
+    let mut validation = validate(&plan, &scan.lockers);
+    if !plan.allow_protected {
+        let denylist = Denylist::new(&[]).with_ancestors(scan.processes.as_slice());
+        let (kills, skipped) = exclude_protected(validation.kills, &denylist);
+        validation.kills = kills;
+        validation.drift.extend(
+            skipped
+                .into_iter()
+                .map(|(locker, reason)| format!("PID {} is now protected: {reason}", locker.pid)),
+        );
+        validation.unlocks.retain(|(locker, _)| {
+            let reason = denylist.check(locker);
+            if let Some(reason) = &reason {
+                validation
+                    .drift
+                    .push(format!("PID {} is now protected: {reason}", locker.pid));
+            }
+            reason.is_none()
+        });
+    }
+
+    for message in &validation.drift {
+        println!("{}", format!("Drift: {message}").yellow());
+    }
+    let mut applied_as_planned = validation.drift.is_empty() && scan.errors.is_empty();
+
+    let action_count = validation.kills.len() + validation.unlocks.len();
+    if action_count == 0 {
+        println!("Nothing in the plan is still valid, nothing to do.");
+        return Ok(applied_as_planned);
+    }
+
+    if args.dry_run {
+        println!("Dry run, no process will be touched.");
+        print_validation(&validation);
+        return Ok(applied_as_planned);
+    }
+
+    let mut audit_log = AuditLog::open(audit_log_path, &plan.target_path)?;
+
+    if !confirm("apply", args.yes, || print_validation(&validation))? {
+        return Ok(false);
+    }
+
+    let mut done_count = 0;
+    if !validation.unlocks.is_empty() {
+        done_count += close_handles(&validation.unlocks, &mut audit_log);
+    }
+    if !validation.kills.is_empty() {
+        done_count += kill_processes(
+            &validation.kills,
+            Duration::from_secs(args.grace_period),
+            Duration::from_secs(args.exit_timeout),
+            &mut audit_log,
+        );
+    }
+    println!("Applied {done_count} of {action_count} action(s).");
+    applied_as_planned &= done_count == action_count;
+
+    Ok(applied_as_planned)
+}
+
+fn print_validation(validation: &Validation) {
+    if !validation.unlocks.is_empty() {
+        println!("The following handle(s) will be closed:");
+        print_handles(&validation.unlocks);
+    }
+    if !validation.kills.is_empty() {
+        println!("The following process(es) will be killed:");
+        for locker in &validation.kills {
+            println!(
+                "  PID: {}, Name: '{}', Path: '{}'",
+                locker.pid,
+                locker.name,
+                locker.display_path()
+            );
+        }
+    }
+}
+
+fn write_plan(plan: &Plan, path: &Path) -> anyhow::Result<()> {
+    let json = serde_json::to_string_pretty(plan).context("Failed to serialize the plan")?;
+    fs::write(path, json + "\n")
+        .with_context(|| format!("Failed to write the plan to '{}'", path.display()))
+}
+
+fn read_plan(path: &Path) -> anyhow::Result<Plan> {
+    let json = fs::read_to_string(path)
+        .with_context(|| format!("Failed to read the plan '{}'", path.display()))?;
+    parse_plan(&json).with_context(|| format!("Invalid plan '{}'", path.display()))
+}
+
+fn parse_plan(json: &str) -> anyhow::Result<Plan> {
+    let plan: Plan = serde_json::from_str(json)?;
+    if plan.version != PLAN_VERSION {
+        return Err(anyhow::anyhow!(
+            "Unsupported plan version {}, expected {PLAN_VERSION}",
+            plan.version
+        ));
+    }
+    Ok(plan)
+}
+
+/// Matches each plan entry against the current lockers by pid and creation time.
+///
+/// Entries whose process exited, was replaced or no longer holds the planned handle are dropped
+/// and reported as drift, as are lockers that are not part of the plan.
+pub fn validate<'a>(plan: &Plan, lockers: &'a BTreeMap<u32, ProcessResult>) -> Validation<'a> {
+    let mut validation = Validation::default();
+    let mut planned = HashSet::new();
+
+    for entry in &plan.entries {
+        planned.insert((entry.pid, entry.create_time));
+
+        let locker = match lockers.get(&entry.pid) {
+            Some(locker) if locker.create_time == entry.create_time => locker,
+            Some(_) => {
+                validation.drift.push(format!(
+                    "{} exited and its pid now belongs to another process, skipped",
+                    entry.describe()
+                ));
+                continue;
+            }
+            None => {
+                validation.drift.push(format!(
+                    "{} no longer locks the path, skipped",
+                    entry.describe()
+                ));
+                continue;
+            }
+        };
+
+        match (entry.action, entry.handle) {
+            (PlanAction::Kill, _) => {
+                if !validation.kills.iter().any(|kill| kill.pid == locker.pid) {
+                    validation.kills.push(locker);
+                }
+            }
+            (PlanAction::Unlock, Some(handle_value)) => {
+                match locker
+                    .handles
+                    .iter()
+                    .find(|handle| handle.handle_value == handle_value)
+                {
+                    Some(handle)
+                        if entry.nt_path.as_ref().is_some_and(|nt_path| {
+                            !nt_path.eq_ignore_ascii_case(&handle.nt_path)
+                        }) =>
+                    {
+                        validation.drift.push(format!(
+                            "{} now points to '{}', skipped",
+                            entry.describe(),
+                            handle.nt_path
+                        ))
+                    }
+                    Some(handle) => validation.unlocks.push((locker, handle)),
+                    None => validation.drift.push(format!(
+                        "{} is no longer open on the path, skipped",
+                        entry.describe()
+                    )),
+                }
+            }
+            (PlanAction::Unlock, None) => validation.drift.push(format!(
+                "unlock entry for {} has no handle, skipped",
+                entry.describe()
+            )),
+        }
+    }
+
+    for locker in lockers.values() {
+        if !planned.contains(&(locker.pid, locker.create_time)) {
+            validation.drift.push(format!(
+                "PID {} ('{}') locks the path but is not in the plan, left alone",
+                locker.pid, locker.name
+            ));
+        }
+    }
+
+    validation
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn locker(pid: u32, create_time: u64, handle_values: &[usize]) -> ProcessResult {
+        ProcessResult {
+            pid,
+            create_time,
+            name: format!("p{pid}.exe"),
+            handles: handle_values
+                .iter()
+                .map(|&handle_value| HandleInfo {
+                    pid,
+                    handle_value,
+                    ..Default::default()
+                })
+                .collect(),
+            ..Default::default()
+        }
+    }
+
+    fn entry(action: PlanAction, pid: u32, create_time: u64, handle: Option<usize>) -> PlanEntry {
+        PlanEntry {
+            action,
+            pid,
+            create_time,
+            name: format!("p{pid}.exe"),
+            image_path: None,
+            handle,
+            nt_path: None,
+        }
+    }
+
+    fn plan(entries: Vec<PlanEntry>) -> Plan {
+        Plan {
+            version: PLAN_VERSION,
+            created: "2024-02-29T12:34:56.789Z".to_string(),
+            target_path: r"C:\build\out.dll".to_string(),
+            target_nt_path: None,
+            allow_protected: false,
+            entries,
+        }
+    }
+
+    fn lockers(lockers: Vec<ProcessResult>) -> BTreeMap<u32, ProcessResult> {
+        lockers.into_iter().map(|l| (l.pid, l)).collect()
+    }
+
+    #[test]
+    fn test_validate_unchanged() {
+        let lockers = lockers(vec![locker(10, 100, &[0x40]), locker(20, 200, &[0x44])]);
+        let plan = plan(vec![
+            entry(PlanAction::Kill, 10, 100, None),
+            entry(PlanAction::Unlock, 20, 200, Some(0x44)),
+        ]);
+        let validation = validate(&plan, &lockers);
+        assert_eq!(
+            validation.kills.iter().map(|l| l.pid).collect::<Vec<_>>(),
+            vec![10]
+        );
+        assert_eq!(
+            validation
+                .unlocks
+                .iter()
+                .map(|(l, h)| (l.pid, h.handle_value))
+                .collect::<Vec<_>>(),
+            vec![(20, 0x44)]
+        );
+        assert!(validation.drift.is_empty());
+    }
+
+    #[test]
+    fn test_validate_drift() {
+        let mut lockers = lockers(vec![
+            locker(10, 999, &[0x40]),
+            locker(20, 200, &[0x48]),
+            locker(40, 400, &[0x4c]),
+            locker(50, 500, &[0x50]),
+        ]);
+        lockers.get_mut(&50).unwrap().handles[0].nt_path =
+            r"\Device\HarddiskVolume3\build\b.dll".to_string();
+        // handle value reused for another file under the target directory
+        let mut reused = entry(PlanAction::Unlock, 50, 500, Some(0x50));
+        reused.nt_path = Some(r"\Device\HarddiskVolume3\build\a.dll".to_string());
+        let plan = plan(vec![
+            // pid reused by a younger process
+            entry(PlanAction::Kill, 10, 100, None),
+            // handle closed and another one opened
+            entry(PlanAction::Unlock, 20, 200, Some(0x44)),
+            // exited
+            entry(PlanAction::Kill, 30, 300, None),
+            reused,
+        ]);
+        let validation = validate(&plan, &lockers);
+        assert!(validation.kills.is_empty());
+        assert!(validation.unlocks.is_empty());
+        // 10 shows up twice, once replaced and once as an unplanned locker.
+        assert_eq!(validation.drift.len(), 6, "{:#?}", validation.drift);
+        assert!(validation.drift[0].contains("PID 10"));
+        assert!(validation.drift[1].contains("handle 0x44 of PID 20"));
+        assert!(validation.drift[2].contains("PID 30"));
+        assert!(validation.drift[3].contains(r"handle 0x50 of PID 50 ('p50.exe') now points to"));
+        assert!(validation.drift[4].contains("PID 10"));
+        assert!(validation.drift[5].contains("PID 40"));
+    }
+
+    #[test]
+    fn test_plan_round_trip() {
+        let mut plan = plan(vec![
+            entry(PlanAction::Kill, 10, 100, None),
+            entry(PlanAction::Unlock, 20, 200, Some(0x44)),
+        ]);
+        plan.target_nt_path = Some(r"\Device\HarddiskVolume3\build\out.dll".to_string());
+        let json = serde_json::to_string_pretty(&plan).unwrap();
+        assert!(json.contains(r#""action": "unlock""#));
+        assert_eq!(parse_plan(&json).unwrap(), plan);
+    }
+
+    #[test]
+    fn test_parse_plan_rejects_other_versions() {
+        let mut plan = plan(Vec::new());
+        plan.version = PLAN_VERSION + 1;
+        let json = serde_json::to_string(&plan).unwrap();
+        assert!(parse_plan(&json).is_err());
+    }
+}
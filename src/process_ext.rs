@@ -1,79 +1,347 @@
+use std::collections::{HashMap, HashSet};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
 use anyhow::{Context, anyhow};
+use log::debug;
+use serde::Serialize;
 use windows::{
-    Wdk::System::SystemInformation::SystemProcessInformation,
+    Wdk::System::{
+        SystemInformation::SystemProcessInformation,
+        Threading::{NtQueryInformationProcess, ProcessBasicInformation},
+    },
     Win32::{
-        Foundation::{ERROR_INSUFFICIENT_BUFFER, GetLastError, HMODULE, MAX_PATH},
+        Foundation::{
+            ERROR_INSUFFICIENT_BUFFER, FILETIME, HMODULE, HWND, LPARAM, MAX_PATH, TRUE,
+            WAIT_OBJECT_0, WPARAM,
+        },
         Security::{
-            GetTokenInformation, LookupAccountSidW, SID_NAME_USE, TOKEN_QUERY, TOKEN_USER,
-            TokenUser,
+            GetTokenInformation, LookupAccountSidW, SID_NAME_USE, TOKEN_ELEVATION, TOKEN_QUERY,
+            TOKEN_USER, TokenElevation, TokenUser,
         },
         System::{
-            ProcessStatus::{EnumProcessModules, GetModuleBaseNameW, GetModuleFileNameExW},
+            Diagnostics::ToolHelp::{
+                CreateToolhelp32Snapshot, TH32CS_SNAPTHREAD, THREADENTRY32, Thread32First,
+                Thread32Next,
+            },
+            ProcessStatus::{EnumProcessModules, GetMappedFileNameW},
             Threading::{
-                OpenProcess, OpenProcessToken, PROCESS_QUERY_INFORMATION,
-                PROCESS_QUERY_LIMITED_INFORMATION, PROCESS_TERMINATE, PROCESS_VM_READ,
-                TerminateProcess,
+                GetCurrentProcess, GetExitCodeProcess, GetProcessTimes, INFINITE,
+                IsProcessCritical, OpenProcess, OpenProcessToken, OpenThread,
+                PROCESS_ACCESS_RIGHTS, PROCESS_BASIC_INFORMATION, PROCESS_NAME_WIN32,
+                PROCESS_QUERY_INFORMATION, PROCESS_QUERY_LIMITED_INFORMATION, PROCESS_SYNCHRONIZE,
+                PROCESS_TERMINATE, PROCESS_VM_READ, QueryFullProcessImageNameW, ResumeThread,
+                SuspendThread, THREAD_SUSPEND_RESUME, TerminateProcess, WaitForSingleObject,
             },
             WindowsProgramming::SYSTEM_PROCESS_INFORMATION,
         },
+        UI::WindowsAndMessaging::{EnumWindows, GetWindowThreadProcessId, PostMessageW, WM_CLOSE},
     },
-    core::{Error, PWSTR},
+    core::{BOOL, Error, PWSTR},
 };
 
 use crate::safe_handle::SafeHandle;
-use crate::{nt_ext, path_ext, string_ext::ToString};
+use crate::{nt_ext, string_ext::ToString};
+
+/// Identifies a process across pid reuse.
+///
+/// Windows recycles pids as soon as a process is gone, but a reused pid always belongs to a
+/// process with a different creation time.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Default)]
+pub struct ProcessIdentity {
+    pub pid: u32,
+    /// Creation time of the process, in 100ns intervals since January 1, 1601 (UTC).
+    pub create_time: u64,
+}
 
+impl ProcessIdentity {
+    /// Reads the identity of the process currently running with `pid`.
+    pub fn query(pid: u32) -> anyhow::Result<Self> {
+        let process_handle = unsafe { OpenProcess(PROCESS_QUERY_LIMITED_INFORMATION, false, pid)? };
+        let safe_process_handle = SafeHandle::new(process_handle);
+        let create_time = get_process_create_time(&safe_process_handle)?;
+        Ok(Self { pid, create_time })
+    }
+
+    /// Opens the process, failing if the pid now belongs to a different process.
+    pub fn open(&self, access: PROCESS_ACCESS_RIGHTS) -> anyhow::Result<SafeHandle> {
+        let process_handle = unsafe {
+            OpenProcess(access | PROCESS_QUERY_LIMITED_INFORMATION, false, self.pid)
+                .with_context(|| format!("OpenProcess failed, pid: {}", self.pid))?
+        };
+        let safe_process_handle = SafeHandle::new(process_handle);
+
+        let create_time = get_process_create_time(&safe_process_handle)?;
+        if create_time != self.create_time {
+            return Err(anyhow!(
+                "Process {} has exited and its pid was reused by another process",
+                self.pid
+            ));
+        }
+
+        Ok(safe_process_handle)
+    }
+}
+
+fn get_process_create_time(safe_process_handle: &SafeHandle) -> anyhow::Result<u64> {
+    let mut creation_time = FILETIME::default();
+    let mut exit_time = FILETIME::default();
+    let mut kernel_time = FILETIME::default();
+    let mut user_time = FILETIME::default();
+    unsafe {
+        GetProcessTimes(
+            safe_process_handle.handle,
+            &mut creation_time,
+            &mut exit_time,
+            &mut kernel_time,
+            &mut user_time,
+        )
+        .context("GetProcessTimes failed")?
+    };
+
+    Ok(((creation_time.dwHighDateTime as u64) << 32) | creation_time.dwLowDateTime as u64)
+}
+
+#[derive(Debug, Default)]
 pub struct ProcessInfo {
     pub pid: u32,
+    /// Pid of the process that created this one, which may have exited since.
+    pub parent_pid: u32,
+    /// Creation time as recorded in the process snapshot, see [`ProcessIdentity`].
+    pub create_time: u64,
     pub process_name: String,
-    pub process_full_path: String,
-    pub modules: Vec<String>,
+    /// Remote desktop session the process runs in, 0 for services.
+    pub session_id: u32,
+    pub counters: ProcessCounters,
 }
 
-pub fn enum_processes() -> anyhow::Result<Vec<ProcessInfo>> {
-    let buffer = nt_ext::nt_query_information_loop(SystemProcessInformation)?;
+/// Counters of a process as of the snapshot.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize)]
+pub struct ProcessCounters {
+    pub handle_count: u32,
+    pub thread_count: u32,
+    /// Bytes of physical memory in use by the process.
+    pub working_set_size: usize,
+}
 
-    let mut process_info_collection = Vec::<ProcessInfo>::new();
+impl ProcessInfo {
+    pub fn identity(&self) -> ProcessIdentity {
+        ProcessIdentity {
+            pid: self.pid,
+            create_time: self.create_time,
+        }
+    }
+}
 
-    let mut offset = 0usize;
-    loop {
-        let process_info: SYSTEM_PROCESS_INFORMATION = unsafe {
-            std::ptr::read_unaligned(
-                buffer.as_ptr().add(offset) as *const SYSTEM_PROCESS_INFORMATION
-            )
+/// The processes of one snapshot indexed by pid, shared by every lookup of a scan so that
+/// describing a process does not require opening it.
+#[derive(Debug, Default)]
+pub struct ProcessTable {
+    /// Ordered by pid.
+    processes: Vec<ProcessInfo>,
+    by_pid: HashMap<u32, usize>,
+}
+
+impl ProcessTable {
+    pub fn capture(max_buffer: usize) -> anyhow::Result<Self> {
+        Ok(process_snapshot(max_buffer)?.collect())
+    }
+
+    pub fn as_slice(&self) -> &[ProcessInfo] {
+        &self.processes
+    }
+
+    pub fn get(&self, pid: u32) -> Option<&ProcessInfo> {
+        self.by_pid.get(&pid).map(|&index| &self.processes[index])
+    }
+
+    /// Returns the identity of the process, queried if it started after the snapshot.
+    pub fn identity(&self, pid: u32) -> ProcessIdentity {
+        match self.get(pid) {
+            Some(process_info) => process_info.identity(),
+            None => ProcessIdentity::query(pid).unwrap_or(ProcessIdentity {
+                pid,
+                create_time: 0,
+            }),
+        }
+    }
+
+    /// Describes the process for the output.
+    ///
+    /// The name and the session come from the snapshot. The image path and the owner need to
+    /// open the process, which is done once. If it cannot be opened, the path and the owner are
+    /// left `None`, and the name still comes from the snapshot, or is "unknown" if the process
+    /// started after it.
+    pub fn describe(&self, identity: &ProcessIdentity) -> ProcessDescription {
+        let safe_process_handle = identity.open(PROCESS_QUERY_LIMITED_INFORMATION);
+        let image_path = safe_process_handle
+            .as_ref()
+            .map_err(|err| anyhow!("{err:#}"))
+            .and_then(query_image_path);
+        let owner = safe_process_handle
+            .as_ref()
+            .ok()
+            .and_then(|safe_process_handle| process_owner(safe_process_handle).ok());
+
+        let snapshot = self
+            .get(identity.pid)
+            .filter(|process_info| process_info.create_time == identity.create_time);
+        let snapshot_name = snapshot
+            .map(|process_info| process_info.process_name.as_str())
+            .filter(|name| !name.is_empty());
+        let name = match (snapshot_name, &image_path) {
+            (Some(name), _) => name.to_string(),
+            (None, Ok(image_path)) => image_name(image_path).to_string(),
+            (None, Err(_)) => "unknown".to_string(),
         };
+        ProcessDescription {
+            name,
+            path: image_path.ok(),
+            parent_pid: snapshot.map(|process_info| process_info.parent_pid),
+            session_id: snapshot.map(|process_info| process_info.session_id),
+            counters: snapshot.map(|process_info| process_info.counters),
+            owner,
+        }
+    }
+}
 
-        if process_info.NextEntryOffset == 0 {
-            break;
+/// What is reported about a process, see [`ProcessTable::describe`].
+#[derive(Debug, Default)]
+pub struct ProcessDescription {
+    pub name: String,
+    /// `None` if the image path could not be queried, the name is not a stand-in for it.
+    pub path: Option<String>,
+    /// This and the other snapshot fields are `None` if the process started after the snapshot.
+    pub parent_pid: Option<u32>,
+    pub session_id: Option<u32>,
+    pub counters: Option<ProcessCounters>,
+    /// `None` if the process or its token cannot be opened.
+    pub owner: Option<ProcessOwner>,
+}
+
+/// The account a process runs as.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize)]
+pub struct ProcessOwner {
+    pub user: String,
+    pub domain: String,
+    /// Whether the process runs with an elevated token, i.e. as administrator under UAC.
+    pub elevated: bool,
+}
+
+impl std::fmt::Display for ProcessOwner {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        if self.domain.is_empty() {
+            write!(f, "{}", self.user)
+        } else {
+            write!(f, "{}\\{}", self.domain, self.user)
         }
+    }
+}
 
-        offset += process_info.NextEntryOffset as usize;
+impl FromIterator<ProcessInfo> for ProcessTable {
+    fn from_iter<I: IntoIterator<Item = ProcessInfo>>(iter: I) -> Self {
+        let mut processes: Vec<ProcessInfo> = iter.into_iter().collect();
+        processes.sort_by_key(|process_info| process_info.pid);
+        let by_pid = processes
+            .iter()
+            .enumerate()
+            .map(|(index, process_info)| (process_info.pid, index))
+            .collect();
+        Self { processes, by_pid }
+    }
+}
 
-        let pid = process_info.UniqueProcessId.0 as u32;
-        let process_name = process_info.ImageName.to_string();
+/// Returns the file name of a Win32 path.
+pub fn image_name(image_path: &str) -> &str {
+    image_path.rsplit('\\').next().unwrap_or(image_path)
+}
 
-        let process_full_path =
-            pid_to_process_full_path(pid).unwrap_or_else(|_| "unknown".to_string());
+/// Captures the running processes, to be walked with the returned iterator.
+pub fn process_snapshot(max_buffer: usize) -> anyhow::Result<ProcessSnapshot> {
+    let buffer = nt_ext::nt_query_information_loop(SystemProcessInformation, max_buffer)?;
+    Ok(ProcessSnapshot {
+        buffer,
+        offset: Some(0),
+    })
+}
+
+/// Iterator over the records of a `SystemProcessInformation` buffer.
+pub struct ProcessSnapshot {
+    buffer: Vec<u8>,
+    /// Offset of the next record, `None` once the last one has been read.
+    offset: Option<usize>,
+}
+
+impl Iterator for ProcessSnapshot {
+    type Item = ProcessInfo;
 
-        let module_nt_paths = enum_process_modules(pid).unwrap_or_else(|_| Vec::new());
-        let process_info = ProcessInfo {
-            pid,
-            process_name,
-            process_full_path,
-            modules: module_nt_paths,
+    fn next(&mut self) -> Option<Self::Item> {
+        let offset = self.offset?;
+        if offset + std::mem::size_of::<SYSTEM_PROCESS_INFORMATION>() > self.buffer.len() {
+            self.offset = None;
+            return None;
+        }
+
+        let process_info: SYSTEM_PROCESS_INFORMATION = unsafe {
+            std::ptr::read_unaligned(
+                self.buffer.as_ptr().add(offset) as *const SYSTEM_PROCESS_INFORMATION
+            )
+        };
+        self.offset = match process_info.NextEntryOffset {
+            0 => None,
+            next_entry_offset => Some(offset + next_entry_offset as usize),
         };
 
-        process_info_collection.push(process_info);
+        // The image name points into the buffer, which lives as long as the iterator.
+        Some(decode_process_info(&process_info))
+    }
+}
+
+/// Decodes one `SystemProcessInformation` record, whose `ImageName` has to point to valid
+/// memory.
+fn decode_process_info(process_info: &SYSTEM_PROCESS_INFORMATION) -> ProcessInfo {
+    ProcessInfo {
+        pid: process_info.UniqueProcessId.0 as u32,
+        // InheritedFromUniqueProcessId, hidden by the SDK as Reserved2.
+        parent_pid: process_info.Reserved2 as usize as u32,
+        create_time: snapshot_create_time(process_info),
+        process_name: process_info.ImageName.to_string(),
+        session_id: process_info.SessionId,
+        counters: ProcessCounters {
+            handle_count: process_info.HandleCount,
+            thread_count: process_info.NumberOfThreads,
+            working_set_size: process_info.WorkingSetSize,
+        },
     }
+}
+
+/// Converts a `FILETIME` value, such as a creation time, to a `SystemTime`. `None` for times
+/// before 1970, including the 0 of an unknown time.
+pub fn filetime_to_system_time(filetime: u64) -> Option<SystemTime> {
+    // 100 ns intervals between 1601-01-01 and 1970-01-01.
+    const UNIX_EPOCH_FILETIME: u64 = 116_444_736_000_000_000;
+    let since_epoch = filetime.checked_sub(UNIX_EPOCH_FILETIME)?;
+    Some(
+        UNIX_EPOCH
+            + Duration::from_secs(since_epoch / 10_000_000)
+            + Duration::from_nanos(since_epoch % 10_000_000 * 100),
+    )
+}
 
-    Ok(process_info_collection)
+/// Reads the `CreateTime` field, which the SDK hides in the reserved bytes of the record.
+fn snapshot_create_time(process_info: &SYSTEM_PROCESS_INFORMATION) -> u64 {
+    // WorkingSetPrivateSize, HardFaultCount, NumberOfThreadsHighWatermark and CycleTime come first.
+    const CREATE_TIME_OFFSET: usize = 24;
+    let bytes = &process_info.Reserved1[CREATE_TIME_OFFSET..CREATE_TIME_OFFSET + 8];
+    u64::from_le_bytes(bytes.try_into().unwrap_or_default())
 }
 
-pub fn enum_process_modules(pid: u32) -> anyhow::Result<Vec<String>> {
+/// Returns the NT paths of the modules loaded in the process.
+///
+/// The paths come from the image mappings, in the same `\Device\...` form as the names of file
+/// handles, so they can be compared without opening each module file.
+pub fn enum_process_modules(identity: &ProcessIdentity) -> anyhow::Result<Vec<String>> {
     // https://learn.microsoft.com/en-us/windows/win32/psapi/enumerating-all-processes
-    let process_handle =
-        unsafe { OpenProcess(PROCESS_QUERY_INFORMATION | PROCESS_VM_READ, false, pid)? };
-    let safe_process_handle = SafeHandle::new(process_handle);
+    let safe_process_handle = identity.open(PROCESS_QUERY_INFORMATION | PROCESS_VM_READ)?;
     let mut buffer = vec![0u8; MAX_PATH as usize];
 
     let mut size_needed = 0u32;
@@ -108,41 +376,42 @@ pub fn enum_process_modules(pid: u32) -> anyhow::Result<Vec<String>> {
                 buffer.as_ptr().add((i * size_of_single_module) as usize) as *const HMODULE
             )
         };
-        let module_name = get_module_name(&safe_process_handle, Some(module))?;
-        let module_nt_path = path_ext::win32_path_to_nt_path(module_name)?;
+        let module_nt_path = get_mapped_file_name(&safe_process_handle, module)?;
         moudle_nt_path_collection.push(module_nt_path);
     }
 
     Ok(moudle_nt_path_collection)
 }
 
-fn get_module_name(
+/// Returns the NT path of the file mapped at the base address of a module.
+fn get_mapped_file_name(
     safe_process_handle: &SafeHandle,
-    module: Option<HMODULE>,
+    module: HMODULE,
 ) -> anyhow::Result<String> {
-    let mut buffer = vec![0u16; MAX_PATH as usize];
-
-    let actual_len =
-        unsafe { GetModuleFileNameExW(Some(safe_process_handle.handle), module, &mut buffer) };
+    // Longest path the object manager accepts, in characters.
+    const MAX_NT_PATH: usize = 32_767;
 
-    if ERROR_INSUFFICIENT_BUFFER == unsafe { GetLastError() } {
-        // Plus one for the null terminator.
-        buffer.resize((actual_len + 1) as usize, 0);
-        unsafe { GetModuleFileNameExW(Some(safe_process_handle.handle), module, &mut buffer) };
-    }
+    let mut buffer = vec![0u16; MAX_PATH as usize];
+    loop {
+        let actual_len =
+            unsafe { GetMappedFileNameW(safe_process_handle.handle, module.0, &mut buffer) }
+                as usize;
+        if actual_len == 0 {
+            return Err(anyhow!(
+                "GetMappedFileNameW failed, error: {}",
+                Error::from_win32()
+            ));
+        }
 
-    if actual_len == 0 {
-        return Err(anyhow!(
-            "GetModuleFileNameExW failed, error: {}",
-            Error::from_win32()
-        ));
+        // A truncated name fills the whole buffer.
+        if actual_len < buffer.len() - 1 || buffer.len() > MAX_NT_PATH {
+            return Ok(String::from_utf16_lossy(&buffer[..actual_len]));
+        }
+        buffer.resize(buffer.len() * 2, 0);
     }
-
-    let module_name = String::from_utf16_lossy(&buffer[..actual_len as usize]);
-    Ok(module_name)
 }
 
-pub fn _pid_to_user(pid: u32) -> anyhow::Result<(String, String)> {
+pub fn pid_to_user(pid: u32) -> anyhow::Result<(String, String)> {
     let open_process_result = unsafe { OpenProcess(PROCESS_QUERY_LIMITED_INFORMATION, false, pid) };
     let process_handle = match open_process_result {
         Ok(handle) => handle,
@@ -152,19 +421,61 @@ pub fn _pid_to_user(pid: u32) -> anyhow::Result<(String, String)> {
     };
 
     let safe_process = SafeHandle::new(process_handle);
+    let safe_token = open_process_token(&safe_process)?;
+    token_user(&safe_token)
+}
+
+/// Returns the `(user, domain)` the process runs as, failing if its pid was reused.
+pub fn process_user(identity: &ProcessIdentity) -> anyhow::Result<(String, String)> {
+    let safe_process_handle = identity.open(PROCESS_QUERY_LIMITED_INFORMATION)?;
+    let safe_token = open_process_token(&safe_process_handle)?;
+    token_user(&safe_token)
+}
 
+/// Returns the account of the process and whether it runs elevated.
+///
+/// The process handle needs `PROCESS_QUERY_LIMITED_INFORMATION`.
+pub fn process_owner(safe_process_handle: &SafeHandle) -> anyhow::Result<ProcessOwner> {
+    let safe_token = open_process_token(safe_process_handle)?;
+    let (user, domain) = token_user(&safe_token)?;
+    Ok(ProcessOwner {
+        user,
+        domain,
+        elevated: token_elevated(&safe_token)?,
+    })
+}
+
+fn open_process_token(safe_process_handle: &SafeHandle) -> anyhow::Result<SafeHandle> {
     let mut token = Default::default();
-    unsafe { OpenProcessToken(safe_process.handle, TOKEN_QUERY, &mut token)? }
-    let safe_token = SafeHandle::new(token);
+    unsafe { OpenProcessToken(safe_process_handle.handle, TOKEN_QUERY, &mut token)? }
+    Ok(SafeHandle::new(token))
+}
+
+fn token_elevated(safe_token: &SafeHandle) -> anyhow::Result<bool> {
+    let mut elevation = TOKEN_ELEVATION::default();
+    let mut return_len = 0u32;
+    unsafe {
+        GetTokenInformation(
+            safe_token.handle,
+            TokenElevation,
+            Some(&mut elevation as *mut TOKEN_ELEVATION as _),
+            std::mem::size_of::<TOKEN_ELEVATION>() as u32,
+            &mut return_len,
+        )
+        .context("GetTokenInformation failed")?
+    };
+    Ok(elevation.TokenIsElevated != 0)
+}
 
+/// Returns the `(user, domain)` of the account a token belongs to.
+fn token_user(safe_token: &SafeHandle) -> anyhow::Result<(String, String)> {
     // Get required buffer size
     let mut token_size = 0u32;
     if let Err(err) =
         unsafe { GetTokenInformation(safe_token.handle, TokenUser, None, 0, &mut token_size) }
+        && err.code() != ERROR_INSUFFICIENT_BUFFER.into()
     {
-        if err.code() != ERROR_INSUFFICIENT_BUFFER.into() {
-            return Err(anyhow!("GetTokenInformation failed with error: {:?}", err));
-        }
+        return Err(anyhow!("GetTokenInformation failed with error: {:?}", err));
     }
 
     // Allocate buffer and get token information
@@ -197,10 +508,9 @@ pub fn _pid_to_user(pid: u32) -> anyhow::Result<(String, String)> {
             &mut domain_size,
             &mut sid_name_use,
         )
-    } {
-        if err.code() != ERROR_INSUFFICIENT_BUFFER.into() {
-            return Err(anyhow!("LookupAccountSidW failed with error: {:?}", err));
-        }
+    } && err.code() != ERROR_INSUFFICIENT_BUFFER.into()
+    {
+        return Err(anyhow!("LookupAccountSidW failed with error: {:?}", err));
     }
 
     // Allocate buffers and get user and domain
@@ -225,52 +535,281 @@ pub fn _pid_to_user(pid: u32) -> anyhow::Result<(String, String)> {
     Ok((user, domain))
 }
 
-pub fn pid_to_process_name(pid: u32) -> anyhow::Result<String> {
-    let process_handle = unsafe {
-        OpenProcess(
-            PROCESS_QUERY_LIMITED_INFORMATION | PROCESS_VM_READ,
-            false,
-            pid,
-        )?
+/// Returns the Win32 path of the process image.
+///
+/// Only needs `PROCESS_QUERY_LIMITED_INFORMATION`, which is granted on most processes that deny
+/// reading their memory.
+pub fn process_image_path(identity: &ProcessIdentity) -> anyhow::Result<String> {
+    let safe_process_handle = identity.open(PROCESS_QUERY_LIMITED_INFORMATION)?;
+    query_image_path(&safe_process_handle)
+}
+
+fn query_image_path(safe_process_handle: &SafeHandle) -> anyhow::Result<String> {
+    let mut buffer = vec![0u16; 32_767];
+    let mut size = buffer.len() as u32;
+    unsafe {
+        QueryFullProcessImageNameW(
+            safe_process_handle.handle,
+            PROCESS_NAME_WIN32,
+            PWSTR(buffer.as_mut_ptr()),
+            &mut size,
+        )
+        .context("QueryFullProcessImageNameW failed")?
     };
-    let safe_process_handle = SafeHandle::new(process_handle);
 
-    let mut buffer = vec![0u16; MAX_PATH as usize];
-    let actual_len = unsafe { GetModuleBaseNameW(safe_process_handle.handle, None, &mut buffer) };
+    Ok(String::from_utf16_lossy(&buffer[..size as usize]))
+}
 
-    if actual_len == 0 {
+/// Returns the pid of the process that started locksmith, usually the shell.
+pub fn current_parent_pid() -> anyhow::Result<u32> {
+    let mut basic_info = PROCESS_BASIC_INFORMATION::default();
+    let mut return_len = 0u32;
+    let nt_status = unsafe {
+        NtQueryInformationProcess(
+            GetCurrentProcess(),
+            ProcessBasicInformation,
+            &mut basic_info as *mut _ as *mut _,
+            std::mem::size_of::<PROCESS_BASIC_INFORMATION>() as u32,
+            &mut return_len,
+        )
+    };
+
+    if nt_status.is_err() {
         return Err(anyhow!(
-            "GetModuleBaseNameW failed, error: {}",
-            Error::from_win32()
+            "NtQueryInformationProcess failed, nt_status: {:?}",
+            nt_status
         ));
     }
 
-    let process_name = String::from_utf16_lossy(&buffer[..actual_len as usize]);
-    Ok(process_name)
+    Ok(basic_info.InheritedFromUniqueProcessId as u32)
 }
 
-pub fn pid_to_process_full_path(pid: u32) -> anyhow::Result<String> {
-    let process_handle = unsafe {
-        OpenProcess(
-            PROCESS_QUERY_LIMITED_INFORMATION | PROCESS_VM_READ,
-            false,
-            pid,
-        )?
-    };
-    let safe_process_handle = SafeHandle::new(process_handle);
+/// Checks whether the process is marked critical, terminating it bugchecks the machine.
+pub fn is_process_critical(identity: &ProcessIdentity) -> anyhow::Result<bool> {
+    let safe_process_handle = identity.open(PROCESS_QUERY_LIMITED_INFORMATION)?;
 
-    get_module_name(&safe_process_handle, None)
+    let mut critical = BOOL::default();
+    unsafe { IsProcessCritical(safe_process_handle.handle, &mut critical)? };
+    Ok(critical.as_bool())
 }
 
-pub fn kill_process_by_pid(pid: u32) -> anyhow::Result<()> {
-    let process_handle = unsafe { OpenProcess(PROCESS_TERMINATE, false, pid)? };
-    let safe_process_handle = SafeHandle::new(process_handle);
+/// The step of [`terminate_process`] that actually ended the process.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TerminationStep {
+    /// The process exited on its own after its windows were asked to close.
+    CloseRequest,
+    /// The process was forcefully terminated with `TerminateProcess`.
+    HardKill,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TerminationOutcome {
+    pub step: TerminationStep,
+    /// Exit code of the process, `None` if it was still running when the exit timeout expired.
+    pub exit_code: Option<u32>,
+}
+
+/// Terminates a process, politely first and forcefully if that does not work.
+///
+/// `WM_CLOSE` is posted to every top-level window of the process, which lets editors save or
+/// prompt for unsaved work, and the process is given `grace_period` to exit. If it has no
+/// window, is still running afterwards, or `grace_period` is zero, it is terminated with
+/// `TerminateProcess` and given `exit_timeout` to actually go away.
+pub fn terminate_process(
+    identity: &ProcessIdentity,
+    grace_period: Duration,
+    exit_timeout: Duration,
+) -> anyhow::Result<TerminationOutcome> {
+    let pid = identity.pid;
+    let safe_process_handle = identity.open(PROCESS_TERMINATE | PROCESS_SYNCHRONIZE)?;
+
+    if !grace_period.is_zero()
+        && request_close_windows(pid) > 0
+        && let Some(exit_code) = wait_for_exit(&safe_process_handle, grace_period)?
+    {
+        return Ok(TerminationOutcome {
+            step: TerminationStep::CloseRequest,
+            exit_code: Some(exit_code),
+        });
+    }
 
     unsafe {
         TerminateProcess(safe_process_handle.handle, 1)
             .context(format!("Failed to terminate process with PID: {pid}"))?
     }
-    Ok(())
+    Ok(TerminationOutcome {
+        step: TerminationStep::HardKill,
+        exit_code: wait_for_exit(&safe_process_handle, exit_timeout)?,
+    })
+}
+
+/// Waits for the process to exit, returns its exit code or `None` on timeout.
+fn wait_for_exit(
+    safe_process_handle: &SafeHandle,
+    timeout: Duration,
+) -> anyhow::Result<Option<u32>> {
+    // Stay below INFINITE so that a huge timeout still expires.
+    let timeout_ms = u32::try_from(timeout.as_millis()).unwrap_or(INFINITE - 1);
+    if unsafe { WaitForSingleObject(safe_process_handle.handle, timeout_ms) } != WAIT_OBJECT_0 {
+        return Ok(None);
+    }
+
+    let mut exit_code = 0u32;
+    unsafe {
+        GetExitCodeProcess(safe_process_handle.handle, &mut exit_code)
+            .context("GetExitCodeProcess failed")?
+    };
+    Ok(Some(exit_code))
+}
+
+/// A process whose threads are suspended, they are resumed when this is dropped.
+pub struct SuspendedProcess {
+    pub identity: ProcessIdentity,
+    // Keeps the pid from being reused while the process is suspended.
+    _process_handle: SafeHandle,
+    threads: Vec<SafeHandle>,
+}
+
+impl SuspendedProcess {
+    pub fn thread_count(&self) -> usize {
+        self.threads.len()
+    }
+
+    /// Resumes every suspended thread, returns how many could not be resumed.
+    pub fn resume(mut self) -> usize {
+        self.resume_threads()
+    }
+
+    fn resume_threads(&mut self) -> usize {
+        let mut failed_count = 0;
+        for thread in self.threads.drain(..) {
+            if unsafe { ResumeThread(thread.handle) } == u32::MAX {
+                debug!(
+                    "ResumeThread failed, pid: {}, error: {}",
+                    self.identity.pid,
+                    Error::from_win32()
+                );
+                failed_count += 1;
+            }
+        }
+        failed_count
+    }
+}
+
+impl Drop for SuspendedProcess {
+    fn drop(&mut self) {
+        self.resume_threads();
+    }
+}
+
+/// Suspends every thread of the process.
+///
+/// Threads are suspended one by one, so the thread list is walked again until no new thread
+/// shows up, in case a running thread started another one in the meantime.
+pub fn suspend_process(identity: &ProcessIdentity) -> anyhow::Result<SuspendedProcess> {
+    const MAX_PASSES: usize = 10;
+
+    let process_handle = identity.open(PROCESS_QUERY_LIMITED_INFORMATION)?;
+    let mut suspended = SuspendedProcess {
+        identity: *identity,
+        _process_handle: process_handle,
+        threads: Vec::new(),
+    };
+
+    let mut suspended_thread_ids = HashSet::new();
+    for _ in 0..MAX_PASSES {
+        let mut new_thread_count = 0;
+        for thread_id in enum_process_threads(identity.pid)? {
+            if suspended_thread_ids.contains(&thread_id) {
+                continue;
+            }
+
+            let thread_handle = match unsafe { OpenThread(THREAD_SUSPEND_RESUME, false, thread_id) }
+            {
+                Ok(thread_handle) => SafeHandle::new(thread_handle),
+                Err(err) => {
+                    // The thread may have exited since the snapshot was taken.
+                    debug!("OpenThread failed, tid: {thread_id}, error: {err:?}");
+                    continue;
+                }
+            };
+            if unsafe { SuspendThread(thread_handle.handle) } == u32::MAX {
+                debug!(
+                    "SuspendThread failed, tid: {thread_id}, error: {}",
+                    Error::from_win32()
+                );
+                continue;
+            }
+
+            suspended_thread_ids.insert(thread_id);
+            suspended.threads.push(thread_handle);
+            new_thread_count += 1;
+        }
+
+        if new_thread_count == 0 {
+            break;
+        }
+    }
+
+    if suspended.threads.is_empty() {
+        return Err(anyhow!(
+            "No thread of process {} could be suspended",
+            identity.pid
+        ));
+    }
+
+    Ok(suspended)
+}
+
+fn enum_process_threads(pid: u32) -> anyhow::Result<Vec<u32>> {
+    let snapshot = unsafe { CreateToolhelp32Snapshot(TH32CS_SNAPTHREAD, 0)? };
+    let safe_snapshot = SafeHandle::new(snapshot);
+
+    let mut thread_ids = Vec::new();
+    let mut entry = THREADENTRY32 {
+        dwSize: std::mem::size_of::<THREADENTRY32>() as u32,
+        ..Default::default()
+    };
+    let mut next = unsafe { Thread32First(safe_snapshot.handle, &mut entry) };
+    while next.is_ok() {
+        if entry.th32OwnerProcessID == pid {
+            thread_ids.push(entry.th32ThreadID);
+        }
+        next = unsafe { Thread32Next(safe_snapshot.handle, &mut entry) };
+    }
+
+    Ok(thread_ids)
+}
+
+/// Posts `WM_CLOSE` to every top-level window owned by `pid`, returns the number of windows.
+fn request_close_windows(pid: u32) -> usize {
+    struct CloseRequest {
+        pid: u32,
+        posted: usize,
+    }
+
+    unsafe extern "system" fn close_window(hwnd: HWND, lparam: LPARAM) -> BOOL {
+        let request = unsafe { &mut *(lparam.0 as *mut CloseRequest) };
+        let mut window_pid = 0u32;
+        unsafe { GetWindowThreadProcessId(hwnd, Some(&mut window_pid)) };
+        if window_pid == request.pid
+            && unsafe { PostMessageW(Some(hwnd), WM_CLOSE, WPARAM(0), LPARAM(0)) }.is_ok()
+        {
+            request.posted += 1;
+        }
+        TRUE
+    }
+
+    let mut request = CloseRequest { pid, posted: 0 };
+    if let Err(err) = unsafe {
+        EnumWindows(
+            Some(close_window),
+            LPARAM(&mut request as *mut CloseRequest as isize),
+        )
+    } {
+        debug!("EnumWindows failed, pid: {pid}, error: {err:?}");
+    }
+    request.posted
 }
 
 #[cfg(test)]
@@ -280,17 +819,186 @@ mod tests {
     // cargo test test_enum_processes -- --nocapture
     #[test]
     fn test_enum_processes() {
-        let process_infos = enum_processes().unwrap();
-        assert!(!process_infos.is_empty());
+        let process_table = ProcessTable::capture(nt_ext::DEFAULT_MAX_BUFFER).unwrap();
+        assert!(!process_table.as_slice().is_empty());
 
-        for process_info in process_infos {
+        for process_info in process_table.as_slice() {
             println!("pid: {}", process_info.pid);
             println!("name: {}", process_info.process_name);
-            println!("path: {}", process_info.process_full_path);
-            for module in process_info.modules {
-                println!("\tmodule: {module}");
-            }
             println!();
         }
     }
+
+    // cargo test test_enum_process_modules -- --nocapture
+    #[test]
+    fn test_enum_process_modules() {
+        let identity = ProcessIdentity::query(std::process::id()).unwrap();
+        let modules = enum_process_modules(&identity).unwrap();
+        assert!(!modules.is_empty());
+
+        for module in &modules {
+            println!("module: {module}");
+            assert!(module.starts_with(r"\Device\"), "{module}");
+        }
+    }
+
+    #[test]
+    fn test_process_snapshot_reads_every_record() {
+        let record_size = std::mem::size_of::<SYSTEM_PROCESS_INFORMATION>();
+        let mut buffer = Vec::new();
+        for (pid, next_entry_offset) in [(0usize, record_size), (4, record_size + 8), (1234, 0)] {
+            let mut process_info = SYSTEM_PROCESS_INFORMATION {
+                NextEntryOffset: next_entry_offset as u32,
+                ..Default::default()
+            };
+            process_info.UniqueProcessId = windows::Win32::Foundation::HANDLE(pid as *mut _);
+            let bytes = unsafe {
+                std::slice::from_raw_parts(
+                    &process_info as *const SYSTEM_PROCESS_INFORMATION as *const u8,
+                    record_size,
+                )
+            };
+            buffer.extend_from_slice(bytes);
+            // Records are not necessarily packed.
+            buffer.resize(
+                buffer.len() + next_entry_offset.saturating_sub(record_size),
+                0,
+            );
+        }
+
+        let snapshot = ProcessSnapshot {
+            buffer,
+            offset: Some(0),
+        };
+        let pids: Vec<u32> = snapshot.map(|process_info| process_info.pid).collect();
+        assert_eq!(pids, vec![0, 4, 1234]);
+    }
+
+    #[test]
+    fn test_process_table_lookup() {
+        let process_table: ProcessTable = [(1234, 10), (4, 0), (88, 20)]
+            .into_iter()
+            .map(|(pid, create_time)| ProcessInfo {
+                pid,
+                create_time,
+                process_name: format!("{pid}.exe"),
+                ..Default::default()
+            })
+            .collect();
+
+        let pids: Vec<u32> = process_table.as_slice().iter().map(|p| p.pid).collect();
+        assert_eq!(pids, vec![4, 88, 1234]);
+        assert_eq!(process_table.get(88).unwrap().process_name, "88.exe");
+        assert!(process_table.get(5).is_none());
+        assert_eq!(
+            process_table.identity(1234),
+            ProcessIdentity {
+                pid: 1234,
+                create_time: 10
+            }
+        );
+    }
+
+    #[test]
+    fn test_describe_falls_back_to_snapshot_name() {
+        let process_table: ProcessTable = [ProcessInfo {
+            pid: 4,
+            process_name: "System".to_string(),
+            ..Default::default()
+        }]
+        .into_iter()
+        .collect();
+
+        // The creation time does not match the real System process, so it cannot be opened.
+        let identity = process_table.identity(4);
+        let description = process_table.describe(&identity);
+        assert_eq!(description.name, "System");
+        assert_eq!(description.path, None);
+        assert_eq!(description.session_id, Some(0));
+        assert_eq!(description.owner, None);
+    }
+
+    #[test]
+    fn test_describe_current_process() {
+        let process_table = ProcessTable::capture(nt_ext::DEFAULT_MAX_BUFFER).unwrap();
+        let identity = process_table.identity(std::process::id());
+        let description = process_table.describe(&identity);
+        let owner = description.owner.unwrap();
+        assert_eq!(
+            (owner.user, owner.domain),
+            pid_to_user(std::process::id()).unwrap()
+        );
+        assert!(description.session_id.is_some());
+    }
+
+    #[test]
+    fn test_owner_display() {
+        let owner = ProcessOwner {
+            user: "alice".to_string(),
+            domain: "CORP".to_string(),
+            elevated: false,
+        };
+        assert_eq!(owner.to_string(), r"CORP\alice");
+    }
+
+    #[test]
+    fn test_image_name() {
+        assert_eq!(image_name(r"C:\Windows\notepad.exe"), "notepad.exe");
+        assert_eq!(image_name("notepad.exe"), "notepad.exe");
+    }
+
+    #[test]
+    fn test_decode_process_info() {
+        let mut image_name: Vec<u16> = "notepad.exe".encode_utf16().collect();
+        let mut process_info = SYSTEM_PROCESS_INFORMATION {
+            NumberOfThreads: 7,
+            UniqueProcessId: windows::Win32::Foundation::HANDLE(1234 as *mut _),
+            Reserved2: 880 as *mut _,
+            HandleCount: 245,
+            SessionId: 2,
+            WorkingSetSize: 24 * 1024 * 1024,
+            ..Default::default()
+        };
+        process_info.ImageName.Length = (image_name.len() * 2) as u16;
+        process_info.ImageName.MaximumLength = process_info.ImageName.Length;
+        process_info.ImageName.Buffer = PWSTR(image_name.as_mut_ptr());
+        process_info.Reserved1[24..32].copy_from_slice(&133_650_000_000_000_000u64.to_le_bytes());
+
+        let decoded = decode_process_info(&process_info);
+        assert_eq!(decoded.pid, 1234);
+        assert_eq!(decoded.parent_pid, 880);
+        assert_eq!(decoded.create_time, 133_650_000_000_000_000);
+        assert_eq!(decoded.process_name, "notepad.exe");
+        assert_eq!(decoded.session_id, 2);
+        assert_eq!(
+            decoded.counters,
+            ProcessCounters {
+                handle_count: 245,
+                thread_count: 7,
+                working_set_size: 24 * 1024 * 1024,
+            }
+        );
+    }
+
+    #[test]
+    fn test_filetime_to_system_time() {
+        assert_eq!(filetime_to_system_time(0), None);
+        assert_eq!(filetime_to_system_time(100), None);
+        assert_eq!(
+            filetime_to_system_time(116_444_736_000_000_000),
+            Some(UNIX_EPOCH)
+        );
+        // 2024-07-08T10:00:00.5Z
+        assert_eq!(
+            filetime_to_system_time(133_649_064_005_000_000),
+            Some(UNIX_EPOCH + Duration::from_millis(1_720_432_800_500))
+        );
+    }
+
+    #[test]
+    fn test_snapshot_create_time() {
+        let mut process_info = SYSTEM_PROCESS_INFORMATION::default();
+        process_info.Reserved1[24..32].copy_from_slice(&0x01DA_0000_1234_5678u64.to_le_bytes());
+        assert_eq!(snapshot_create_time(&process_info), 0x01DA_0000_1234_5678);
+    }
 }
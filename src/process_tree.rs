@@ -0,0 +1,210 @@
+use std::collections::{HashMap, HashSet};
+
+use crate::process_ext::ProcessInfo;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TreeEntry {
+    pub pid: u32,
+    /// 0 for the roots, 1 for their children and so on.
+    pub depth: usize,
+}
+
+/// Walks the process trees rooted at `roots`, parents before children.
+///
+/// Reversing the result gives an order in which every child comes before its parent. A root that
+/// is itself a descendant of another root only appears once, under that root.
+///
+/// A process only counts as a child if it was created after its parent, since the parent pid
+/// recorded in the snapshot may already have been reused by an unrelated, younger process.
+pub fn walk_trees(processes: &[ProcessInfo], roots: &[u32]) -> Vec<TreeEntry> {
+    let by_pid: HashMap<u32, &ProcessInfo> = processes.iter().map(|p| (p.pid, p)).collect();
+
+    let mut children = HashMap::<u32, Vec<u32>>::new();
+    for process in processes {
+        let Some(parent) = by_pid.get(&process.parent_pid) else {
+            continue;
+        };
+        if parent.create_time < process.create_time {
+            children.entry(parent.pid).or_default().push(process.pid);
+        }
+    }
+    for siblings in children.values_mut() {
+        siblings.sort_unstable();
+    }
+
+    // Roots reachable from another root are walked as part of that root's tree.
+    let mut nested = HashSet::new();
+    for &root in roots {
+        let mut stack = children.get(&root).cloned().unwrap_or_default();
+        while let Some(pid) = stack.pop() {
+            if nested.insert(pid) {
+                stack.extend(children.get(&pid).into_iter().flatten());
+            }
+        }
+    }
+
+    let mut visited = HashSet::new();
+    let mut entries = Vec::new();
+    for &root in roots {
+        if nested.contains(&root) {
+            continue;
+        }
+
+        let mut stack = vec![TreeEntry {
+            pid: root,
+            depth: 0,
+        }];
+        while let Some(entry) = stack.pop() {
+            if !visited.insert(entry.pid) {
+                continue;
+            }
+            entries.push(entry);
+            if let Some(siblings) = children.get(&entry.pid) {
+                stack.extend(siblings.iter().rev().map(|&pid| TreeEntry {
+                    pid,
+                    depth: entry.depth + 1,
+                }));
+            }
+        }
+    }
+    entries
+}
+
+/// Drops the entries for which `is_pruned` returns true from a walk, along with everything
+/// below them.
+///
+/// `tree` must be in the parents-first order of [`walk_trees`].
+pub fn prune_subtrees(
+    tree: &[TreeEntry],
+    mut is_pruned: impl FnMut(&TreeEntry) -> bool,
+) -> Vec<TreeEntry> {
+    let mut kept = Vec::with_capacity(tree.len());
+    // Depth of the entry being pruned, until the walk climbs back above it.
+    let mut pruned_depth = None;
+    for entry in tree {
+        if pruned_depth.is_some_and(|depth| entry.depth > depth) {
+            continue;
+        }
+        pruned_depth = None;
+        if is_pruned(entry) {
+            pruned_depth = Some(entry.depth);
+        } else {
+            kept.push(*entry);
+        }
+    }
+    kept
+}
+
+/// Returns the parent, grandparent and so on of `pid`, closest first.
+///
+/// The chain stops at the first parent pid that is missing from the snapshot or was reused by a
+/// younger process.
+pub fn ancestors(processes: &[ProcessInfo], pid: u32) -> Vec<u32> {
+    let by_pid: HashMap<u32, &ProcessInfo> = processes.iter().map(|p| (p.pid, p)).collect();
+
+    let mut chain = Vec::new();
+    let mut current = by_pid.get(&pid);
+    while let Some(process) = current {
+        let parent = by_pid
+            .get(&process.parent_pid)
+            .filter(|parent| parent.create_time < process.create_time);
+        if let Some(parent) = parent {
+            chain.push(parent.pid);
+        }
+        current = parent;
+    }
+    chain
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn process(pid: u32, parent_pid: u32, create_time: u64) -> ProcessInfo {
+        ProcessInfo {
+            pid,
+            parent_pid,
+            create_time,
+            ..Default::default()
+        }
+    }
+
+    fn pids(entries: &[TreeEntry]) -> Vec<(u32, usize)> {
+        entries.iter().map(|e| (e.pid, e.depth)).collect()
+    }
+
+    #[test]
+    fn test_walk_trees_parents_before_children() {
+        let processes = vec![
+            process(1, 0, 1),
+            process(10, 1, 10),
+            process(11, 10, 11),
+            process(12, 10, 12),
+            process(13, 11, 13),
+            process(20, 1, 20),
+        ];
+        let entries = walk_trees(&processes, &[10]);
+        assert_eq!(pids(&entries), vec![(10, 0), (11, 1), (13, 2), (12, 1)]);
+    }
+
+    #[test]
+    fn test_walk_trees_skips_reused_parent_pid() {
+        // 30 claims 10 as its parent, but was created before the current process 10.
+        let processes = vec![process(10, 1, 100), process(30, 10, 50)];
+        let entries = walk_trees(&processes, &[10]);
+        assert_eq!(pids(&entries), vec![(10, 0)]);
+    }
+
+    #[test]
+    fn test_walk_trees_nested_roots_appear_once() {
+        let processes = vec![process(10, 1, 10), process(11, 10, 11), process(12, 11, 12)];
+        let entries = walk_trees(&processes, &[11, 10]);
+        assert_eq!(pids(&entries), vec![(10, 0), (11, 1), (12, 2)]);
+    }
+
+    #[test]
+    fn test_walk_trees_unknown_root() {
+        let entries = walk_trees(&[], &[42]);
+        assert_eq!(pids(&entries), vec![(42, 0)]);
+    }
+
+    #[test]
+    fn test_prune_subtrees_drops_protected_root_and_children() {
+        let processes = vec![
+            process(10, 1, 10),
+            process(11, 10, 11),
+            process(12, 11, 12),
+            process(20, 1, 20),
+            process(21, 20, 21),
+        ];
+        let tree = walk_trees(&processes, &[10, 20]);
+        let kept = prune_subtrees(&tree, |entry| entry.pid == 10);
+        assert_eq!(pids(&kept), vec![(20, 0), (21, 1)]);
+    }
+
+    #[test]
+    fn test_prune_subtrees_keeps_siblings_of_pruned_child() {
+        let processes = vec![
+            process(10, 1, 10),
+            process(11, 10, 11),
+            process(12, 11, 12),
+            process(13, 10, 13),
+        ];
+        let tree = walk_trees(&processes, &[10]);
+        let kept = prune_subtrees(&tree, |entry| entry.pid == 11);
+        assert_eq!(pids(&kept), vec![(10, 0), (13, 1)]);
+    }
+
+    #[test]
+    fn test_ancestors_stops_at_reused_parent_pid() {
+        let processes = vec![
+            // 1 was created after 2, so it cannot be the parent of 2.
+            process(1, 0, 50),
+            process(2, 1, 5),
+            process(3, 2, 10),
+            process(4, 3, 20),
+        ];
+        assert_eq!(ancestors(&processes, 4), vec![3, 2]);
+        assert_eq!(ancestors(&processes, 99), Vec::<u32>::new());
+    }
+}
@@ -0,0 +1,234 @@
+use std::str::FromStr;
+
+use crate::process_ext::{self, ProcessInfo};
+use crate::{ProcessResult, process_tree};
+
+/// Processes whose termination bluescreens the machine or ends the user's session.
+const BUILTIN_PROTECTED_NAMES: &[&str] = &[
+    "System",
+    "Registry",
+    "Secure System",
+    "Memory Compression",
+    "smss.exe",
+    "csrss.exe",
+    "wininit.exe",
+    "winlogon.exe",
+    "services.exe",
+    "lsass.exe",
+    "lsaiso.exe",
+];
+
+/// The System Idle Process and the System process.
+const BUILTIN_PROTECTED_PIDS: &[u32] = &[0, 4];
+
+/// A user supplied `--protect` entry.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ProtectRule {
+    Pid(u32),
+    Name(String),
+    Path(String),
+}
+
+impl FromStr for ProtectRule {
+    type Err = String;
+
+    /// Numbers are pids, values containing a path separator are image paths, anything else
+    /// is an image name.
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        let value = value.trim();
+        if value.is_empty() {
+            return Err("protect rule cannot be empty".to_string());
+        }
+
+        if let Ok(pid) = value.parse::<u32>() {
+            Ok(ProtectRule::Pid(pid))
+        } else if value.contains(['\\', '/']) {
+            Ok(ProtectRule::Path(value.replace('/', "\\")))
+        } else {
+            Ok(ProtectRule::Name(value.to_string()))
+        }
+    }
+}
+
+impl ProtectRule {
+    fn matches(&self, locker: &ProcessResult) -> bool {
+        match self {
+            ProtectRule::Pid(pid) => *pid == locker.pid,
+            ProtectRule::Name(name) => name.eq_ignore_ascii_case(&locker.name),
+            ProtectRule::Path(path) => locker
+                .path
+                .as_deref()
+                .is_some_and(|image_path| path.eq_ignore_ascii_case(image_path)),
+        }
+    }
+}
+
+/// The processes `--kill` refuses to touch unless `--allow-protected` is given.
+pub struct Denylist {
+    self_pid: u32,
+    parent_pid: Option<u32>,
+    ancestor_pids: Vec<u32>,
+    user_rules: Vec<ProtectRule>,
+}
+
+impl Denylist {
+    pub fn new(user_rules: &[ProtectRule]) -> Self {
+        Self {
+            self_pid: std::process::id(),
+            parent_pid: process_ext::current_parent_pid().ok(),
+            ancestor_pids: Vec::new(),
+            user_rules: user_rules.to_vec(),
+        }
+    }
+
+    /// Also protects the whole ancestor chain of locksmith found in the snapshot, such as the
+    /// terminal and explorer.exe, not just its direct parent.
+    pub fn with_ancestors(mut self, processes: &[ProcessInfo]) -> Self {
+        self.ancestor_pids = process_tree::ancestors(processes, self.self_pid);
+        self
+    }
+
+    /// Returns why the locker must not be killed, or `None` if it is fair game.
+    pub fn check(&self, locker: &ProcessResult) -> Option<String> {
+        if let Some(reason) = self.match_rules(locker) {
+            return Some(reason);
+        }
+
+        match process_ext::is_process_critical(&locker.identity()) {
+            Ok(true) => Some("marked as a critical process".to_string()),
+            _ => None,
+        }
+    }
+
+    /// Matches the locker against every rule that does not need to open the process.
+    fn match_rules(&self, locker: &ProcessResult) -> Option<String> {
+        if BUILTIN_PROTECTED_PIDS.contains(&locker.pid) {
+            return Some(format!(
+                "PID {} is a built-in protected process",
+                locker.pid
+            ));
+        }
+
+        if let Some(name) = BUILTIN_PROTECTED_NAMES
+            .iter()
+            .find(|name| name.eq_ignore_ascii_case(&locker.name))
+        {
+            return Some(format!("'{name}' is a built-in protected process"));
+        }
+
+        if locker.pid == self.self_pid {
+            return Some("this is locksmith itself".to_string());
+        }
+
+        if Some(locker.pid) == self.parent_pid {
+            return Some("this is the parent process of locksmith".to_string());
+        }
+
+        if self.ancestor_pids.contains(&locker.pid) {
+            return Some("this is an ancestor process of locksmith".to_string());
+        }
+
+        self.user_rules
+            .iter()
+            .find(|rule| rule.matches(locker))
+            .map(|rule| match rule {
+                ProtectRule::Pid(pid) => format!("PID {pid} is protected by --protect"),
+                ProtectRule::Name(name) => format!("'{name}' is protected by --protect"),
+                ProtectRule::Path(path) => format!("'{path}' is protected by --protect"),
+            })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn locker(pid: u32, name: &str, path: &str) -> ProcessResult {
+        ProcessResult {
+            pid,
+            name: name.to_string(),
+            path: Some(path.to_string()),
+            ..Default::default()
+        }
+    }
+
+    fn denylist(user_rules: Vec<ProtectRule>) -> Denylist {
+        Denylist {
+            self_pid: 100,
+            parent_pid: Some(200),
+            ancestor_pids: vec![200, 250],
+            user_rules,
+        }
+    }
+
+    #[test]
+    fn test_protect_rule_from_str() {
+        assert_eq!("1234".parse(), Ok(ProtectRule::Pid(1234)));
+        assert_eq!(
+            "devenv.exe".parse(),
+            Ok(ProtectRule::Name("devenv.exe".to_string()))
+        );
+        assert_eq!(
+            "C:/Tools/agent.exe".parse(),
+            Ok(ProtectRule::Path(r"C:\Tools\agent.exe".to_string()))
+        );
+        assert!(" ".parse::<ProtectRule>().is_err());
+    }
+
+    #[test]
+    fn test_match_rules_builtin() {
+        let denylist = denylist(Vec::new());
+        assert!(denylist.match_rules(&locker(4, "System", "")).is_some());
+        assert!(
+            denylist
+                .match_rules(&locker(700, "CSRSS.EXE", r"C:\Windows\System32\csrss.exe"))
+                .is_some()
+        );
+        assert!(
+            denylist
+                .match_rules(&locker(800, "notepad.exe", r"C:\Windows\notepad.exe"))
+                .is_none()
+        );
+    }
+
+    #[test]
+    fn test_match_rules_self_and_ancestors() {
+        let denylist = denylist(Vec::new());
+        assert!(
+            denylist
+                .match_rules(&locker(100, "locksmith.exe", ""))
+                .is_some()
+        );
+        assert!(denylist.match_rules(&locker(200, "pwsh.exe", "")).is_some());
+        assert!(
+            denylist
+                .match_rules(&locker(250, "explorer.exe", ""))
+                .is_some()
+        );
+    }
+
+    #[test]
+    fn test_match_rules_user() {
+        let denylist = denylist(vec![
+            ProtectRule::Pid(300),
+            ProtectRule::Name("devenv.exe".to_string()),
+            ProtectRule::Path(r"C:\Tools\agent.exe".to_string()),
+        ]);
+        assert!(denylist.match_rules(&locker(300, "a.exe", "")).is_some());
+        assert!(
+            denylist
+                .match_rules(&locker(301, "DevEnv.exe", ""))
+                .is_some()
+        );
+        assert!(
+            denylist
+                .match_rules(&locker(302, "agent.exe", r"c:\tools\AGENT.exe"))
+                .is_some()
+        );
+        assert!(
+            denylist
+                .match_rules(&locker(303, "agent.exe", r"C:\Other\agent.exe"))
+                .is_none()
+        );
+    }
+}
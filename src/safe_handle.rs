@@ -11,6 +11,9 @@ impl SafeHandle {
     }
 }
 
+// SAFETY: a kernel handle is valid on every thread of the process that owns it.
+unsafe impl Send for SafeHandle {}
+
 impl Drop for SafeHandle {
     fn drop(&mut self) {
         unsafe {
@@ -0,0 +1,125 @@
+use colored::Colorize;
+
+use crate::audit::{AuditAction, AuditLog};
+use crate::handle_ext::{self, HandleInfo};
+use crate::kill::{confirm, exclude_protected, print_skipped, verify_released};
+use crate::protect::Denylist;
+use crate::{Cli, ProcessResult, ScanResult};
+
+/// Closes the matching handles inside their owning processes instead of killing them.
+pub fn confirm_and_unlock(cli: &Cli, scan: &ScanResult) -> anyhow::Result<()> {
+    let lockers = &scan.lockers;
+    let targets: Vec<_> = lockers.values().collect();
+    let (targets, skipped) = if cli.allow_protected {
+        (targets, Vec::new())
+    } else {
+        exclude_protected(
+            targets,
+            &Denylist::new(&cli.protect).with_ancestors(scan.processes.as_slice()),
+        )
+    };
+    print_skipped(&skipped);
+
+    let handles = closable_handles(targets);
+
+    if handles.is_empty() {
+        println!("No handle can be closed, nothing to unlock.");
+        return Ok(());
+    }
+
+    if cli.dry_run {
+        println!("Dry run, no handle will be closed.");
+        println!("The following handle(s) would be closed:");
+        print_handles(&handles);
+        return Ok(());
+    }
+
+    let mut audit_log = AuditLog::open(cli.audit_log.as_deref(), &cli.audit_target())?;
+
+    if !confirm("unlock", cli.yes, || {
+        println!("The following handle(s) will be closed:");
+        print_handles(&handles);
+        println!(
+            "{}",
+            "WARNING: Closing a handle behind a process's back can make it crash or corrupt the data it was writing."
+                .bold()
+                .yellow()
+        );
+    })? {
+        return Ok(());
+    }
+
+    let closed_count = close_handles(&handles, &mut audit_log);
+    println!("Closed {closed_count} of {} handle(s).", handles.len());
+
+    verify_released(cli, lockers)
+}
+
+/// Collects the handles of the targets that can be closed, explaining why the others cannot.
+pub fn closable_handles(targets: Vec<&ProcessResult>) -> Vec<(&ProcessResult, &HandleInfo)> {
+    let mut handles = Vec::new();
+    for target in targets {
+        if !target.modules.is_empty() {
+            println!(
+                "{}",
+                format!(
+                    "PID {} ('{}') has the path loaded as a module, which cannot be unlocked, use --kill instead.",
+                    target.pid, target.name
+                )
+                .yellow()
+            );
+        }
+        for handle in &target.handles {
+            if handle.is_protected_from_close() {
+                println!(
+                    "{}",
+                    format!(
+                        "Handle 0x{:x} of PID {} ('{}') is protected from close and will be left open.",
+                        handle.handle_value, target.pid, target.name
+                    )
+                    .yellow()
+                );
+            } else {
+                handles.push((target, handle));
+            }
+        }
+    }
+    handles
+}
+
+/// Closes the handles, returns how many of them were closed.
+pub fn close_handles(handles: &[(&ProcessResult, &HandleInfo)], audit_log: &mut AuditLog) -> usize {
+    let mut closed_count = 0;
+    for (locker, handle) in handles {
+        let result = handle_ext::close_remote_handle(&locker.identity(), handle);
+        audit_log.record(
+            locker,
+            AuditAction::Unlock,
+            Some(handle.handle_value),
+            &result,
+        );
+        match result {
+            Ok(()) => {
+                println!(
+                    "Closed handle 0x{:x} of PID {}.",
+                    handle.handle_value, handle.pid
+                );
+                closed_count += 1;
+            }
+            Err(e) => eprintln!(
+                "Failed to close handle 0x{:x} of PID {}: {e:#}",
+                handle.handle_value, handle.pid
+            ),
+        }
+    }
+    closed_count
+}
+
+pub fn print_handles(handles: &[(&ProcessResult, &HandleInfo)]) {
+    for (locker, handle) in handles {
+        println!(
+            "  Handle 0x{:x} of PID {} ('{}'): {}",
+            handle.handle_value, locker.pid, locker.name, handle.nt_path
+        );
+    }
+}
@@ -0,0 +1,114 @@
+use std::sync::mpsc::{self, Receiver, RecvTimeoutError, Sender};
+use std::thread;
+use std::time::Duration;
+
+use log::debug;
+
+/// Runs a query that may block forever on a helper thread, under a deadline.
+///
+/// A helper that misses the deadline is abandoned, still blocked, and the next query starts a
+/// new one. The abandoned thread exits on its own if the query ever returns.
+pub struct Watchdog<T, R> {
+    query: fn(T) -> R,
+    timeout: Duration,
+    worker: Option<Worker<T, R>>,
+}
+
+struct Worker<T, R> {
+    requests: Sender<T>,
+    results: Receiver<R>,
+}
+
+impl<T: Send + 'static, R: Send + 'static> Watchdog<T, R> {
+    pub fn new(query: fn(T) -> R, timeout: Duration) -> Self {
+        Self {
+            query,
+            timeout,
+            worker: None,
+        }
+    }
+
+    /// Runs the query, returns `None` if it did not finish before the deadline.
+    pub fn run(&mut self, request: T) -> Option<R> {
+        let worker = match &mut self.worker {
+            Some(worker) => worker,
+            None => self.worker.insert(Worker::spawn(self.query)?),
+        };
+
+        if worker.requests.send(request).is_err() {
+            self.worker = None;
+            return None;
+        }
+        match worker.results.recv_timeout(self.timeout) {
+            Ok(result) => Some(result),
+            Err(RecvTimeoutError::Timeout | RecvTimeoutError::Disconnected) => {
+                self.worker = None;
+                None
+            }
+        }
+    }
+}
+
+impl<T: Send + 'static, R: Send + 'static> Worker<T, R> {
+    fn spawn(query: fn(T) -> R) -> Option<Self> {
+        let (requests, request_receiver) = mpsc::channel::<T>();
+        let (result_sender, results) = mpsc::channel();
+        let spawn_result = thread::Builder::new()
+            .name("watchdog-query".to_string())
+            .spawn(move || {
+                for request in request_receiver {
+                    if result_sender.send(query(request)).is_err() {
+                        break;
+                    }
+                }
+            });
+        match spawn_result {
+            Ok(_) => Some(Self { requests, results }),
+            Err(err) => {
+                debug!("Failed to spawn a watchdog query thread, error: {err:?}");
+                None
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Instant;
+
+    /// Blocks for as many milliseconds as requested, then echoes the request.
+    fn sleepy(millis: u64) -> u64 {
+        thread::sleep(Duration::from_millis(millis));
+        millis
+    }
+
+    #[test]
+    fn test_watchdog_returns_results_in_time() {
+        let mut watchdog = Watchdog::new(sleepy, Duration::from_secs(5));
+        assert_eq!(watchdog.run(0), Some(0));
+        assert_eq!(watchdog.run(1), Some(1));
+    }
+
+    #[test]
+    fn test_watchdog_abandons_stuck_query() {
+        let mut watchdog = Watchdog::new(sleepy, Duration::from_millis(50));
+
+        let start = Instant::now();
+        assert_eq!(watchdog.run(60_000), None);
+        assert!(start.elapsed() < Duration::from_secs(5));
+
+        // The stuck helper is replaced, later queries still get their own answer.
+        assert_eq!(watchdog.run(0), Some(0));
+        assert_eq!(watchdog.run(2), Some(2));
+    }
+
+    #[test]
+    fn test_watchdog_ignores_late_results() {
+        let mut watchdog = Watchdog::new(sleepy, Duration::from_millis(20));
+        assert_eq!(watchdog.run(200), None);
+        thread::sleep(Duration::from_millis(300));
+        // The late answer of the abandoned helper must not be taken for this one.
+        assert_eq!(watchdog.run(1), Some(1));
+    }
+}